@@ -0,0 +1 @@
+DROP TABLE milk_bucket;
\ No newline at end of file
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS milk_bucket (
+    id TEXT PRIMARY KEY,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO milk_bucket (id, tokens) VALUES ('milk', 5) ON CONFLICT (id) DO NOTHING;
@@ -0,0 +1,2 @@
+DROP TABLE milk_ledger;
+DROP TABLE milk_stock;
\ No newline at end of file
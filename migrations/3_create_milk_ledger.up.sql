@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS milk_stock (
+    id TEXT PRIMARY KEY,
+    liters NUMERIC NOT NULL
+);
+
+INSERT INTO milk_stock (id, liters) VALUES ('milk', 0) ON CONFLICT (id) DO NOTHING;
+
+CREATE TABLE IF NOT EXISTS milk_ledger (
+    id BIGSERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    liters NUMERIC NOT NULL,
+    stock NUMERIC NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,4 @@
+DROP TABLE game_ratings;
+DROP TABLE players;
+DROP TABLE game_moves;
+DROP TABLE games;
\ No newline at end of file
@@ -0,0 +1,39 @@
+CREATE TABLE IF NOT EXISTS games (
+    id UUID PRIMARY KEY,
+    start TEXT NOT NULL,
+    milk_player TEXT,
+    cookie_player TEXT,
+    result TEXT,
+    version BIGINT NOT NULL,
+    round BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_games_updated_at ON games(updated_at);
+
+CREATE TABLE IF NOT EXISTS game_moves (
+    game_id UUID NOT NULL REFERENCES games(id) ON DELETE CASCADE,
+    ply INT NOT NULL,
+    team TEXT NOT NULL,
+    column_index INT NOT NULL,
+    PRIMARY KEY (game_id, ply)
+);
+
+CREATE TABLE IF NOT EXISTS players (
+    name TEXT PRIMARY KEY,
+    rating DOUBLE PRECISION NOT NULL DEFAULT 1500,
+    wins INT NOT NULL DEFAULT 0,
+    losses INT NOT NULL DEFAULT 0,
+    draws INT NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS game_ratings (
+    game_id UUID NOT NULL REFERENCES games(id) ON DELETE CASCADE,
+    round BIGINT NOT NULL,
+    milk_player TEXT NOT NULL,
+    cookie_player TEXT NOT NULL,
+    score DOUBLE PRECISION NOT NULL,
+    delta DOUBLE PRECISION NOT NULL,
+    PRIMARY KEY (game_id, round)
+);
@@ -0,0 +1 @@
+ALTER TABLE game_moves DROP COLUMN row_index, DROP COLUMN kind;
\ No newline at end of file
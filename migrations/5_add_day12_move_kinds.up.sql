@@ -0,0 +1,3 @@
+ALTER TABLE game_moves
+    ADD COLUMN IF NOT EXISTS kind TEXT NOT NULL DEFAULT 'drop',
+    ADD COLUMN IF NOT EXISTS row_index INT;
@@ -1,38 +1,212 @@
-use axum::extract::Path;
-use axum::http::StatusCode;
+mod ai;
+mod store;
+mod tournament;
+
+use askama::Template;
+use axum::extract::{Path, Query};
+use axum::http::header::ACCEPT;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::Html;
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use std::collections::{BTreeSet, HashMap};
+use std::convert::Infallible;
 use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
 
 const WALL: char = '⬜';
 const EMPTY: char = '⬛';
 const COOKIE: char = '🍪';
 const MILK: char = '🥛';
 
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Team {
     Milk,
     Cookie,
 }
 
+impl FromStr for Team {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "milk" => Ok(Team::Milk),
+            "cookie" => Ok(Team::Cookie),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Team {
+    fn name(self) -> &'static str {
+        match self {
+            Team::Milk => "milk",
+            Team::Cookie => "cookie",
+        }
+    }
+
+    fn other(self) -> Team {
+        match self {
+            Team::Milk => Team::Cookie,
+            Team::Cookie => Team::Milk,
+        }
+    }
+
+    fn symbol(self) -> char {
+        match self {
+            Team::Milk => MILK,
+            Team::Cookie => COOKIE,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PlaceError {
+    GameOver,
+    ColumnFull,
+    WrongTurn,
+    OutOfRange,
+    // The move exists but the game's variant doesn't allow it here.
+    Illegal,
+}
+
+impl PlaceError {
+    fn status(&self) -> StatusCode {
+        match self {
+            PlaceError::GameOver | PlaceError::ColumnFull => StatusCode::SERVICE_UNAVAILABLE,
+            PlaceError::WrongTurn => StatusCode::CONFLICT,
+            PlaceError::OutOfRange => StatusCode::BAD_REQUEST,
+            PlaceError::Illegal => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+// Rules sharing the grid: `popout` also allows removing your own disc from
+// the bottom of a column, `gravity-free` places discs on any empty cell and
+// in `misere` completing a line loses.
+#[derive(PartialEq, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Variant {
+    #[default]
+    Standard,
+    PopOut,
+    #[serde(rename = "gravity-free")]
+    GravityFree,
+    Misere,
+}
+
+impl FromStr for Variant {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "standard" => Ok(Variant::Standard),
+            "popout" => Ok(Variant::PopOut),
+            "gravity-free" => Ok(Variant::GravityFree),
+            "misere" => Ok(Variant::Misere),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Variant {
+    fn name(self) -> &'static str {
+        match self {
+            Variant::Standard => "standard",
+            Variant::PopOut => "popout",
+            Variant::GravityFree => "gravity-free",
+            Variant::Misere => "misere",
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum Action {
+    Drop(usize),
+    Pop(usize),
+    Put(usize, usize),
+}
+
+impl Action {
+    fn column(self) -> usize {
+        match self {
+            Action::Drop(column) | Action::Pop(column) | Action::Put(_, column) => column,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Move {
+    team: Team,
+    action: Action,
+    row: usize,
+    column: usize,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Terminal {
     Draw,
     Win(Team),
+    // Both teams have a winning line, which only happens in random or
+    // loaded positions.
+    Both,
+}
+
+impl Terminal {
+    fn message(&self) -> &'static str {
+        match self {
+            Terminal::Draw => "No winner.",
+            Terminal::Win(Team::Milk) => "🥛 wins!",
+            Terminal::Win(Team::Cookie) => "🍪 wins!",
+            Terminal::Both => "Both 🥛 and 🍪 win, invalid position.",
+        }
+    }
 }
 
-#[derive(Default)]
+const DIRECTIONS: [(isize, isize); 4] = [(1, 0), (0, 1), (1, 1), (1, -1)];
+const MAX_SIZE: usize = 16;
+// Most cells a single random-board request may generate across all boards.
+const MAX_RANDOM_CELLS: usize = 100_000;
+
+#[derive(Clone)]
 pub struct Board {
-    cells: [[Option<Team>; 4]; 4],
+    width: usize,
+    height: usize,
+    connect: usize,
+    cells: Vec<Vec<Option<Team>>>,
+    variant: Variant,
     terminal: Option<Terminal>,
+    winning_cells: Vec<(usize, usize)>,
+    // Team that moves first when turn order is enforced.
+    first: Option<Team>,
+    moves: Vec<Move>,
+    // Times the game was reset or reloaded, so each round is rated on its own.
+    round: i64,
+}
+
+impl Default for Board {
+    fn default() -> Self {
+        Board::new(4, 4, 4).unwrap()
+    }
 }
 
 impl Display for Board {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        for row in 0..4 {
+        for row in &self.cells {
             write!(f, "{}", WALL)?;
-            for column in 0..4 {
-                match self.cells[row][column] {
+            for cell in row {
+                match cell {
                     Some(Team::Milk) => write!(f, "{}", MILK)?,
                     Some(Team::Cookie) => write!(f, "{}", COOKIE)?,
                     None => write!(f, "{}", EMPTY)?,
@@ -40,110 +214,479 @@ impl Display for Board {
             }
             writeln!(f, "{}", WALL)?;
         }
-        writeln!(f, "{}", WALL.to_string().repeat(6))?;
+        writeln!(f, "{}", WALL.to_string().repeat(self.width + 2))?;
         if let Some(terminal) = &self.terminal {
-            write!(
-                f,
-                "{}",
-                match terminal {
-                    Terminal::Draw => "No winner.\n",
-                    Terminal::Win(Team::Milk) => "🥛 wins!\n",
-                    Terminal::Win(Team::Cookie) => "🍪 wins!\n",
-                }
-            )?;
+            writeln!(f, "{}", terminal.message())?;
         }
         Ok(())
     }
 }
 
-impl Board {
-    fn place(&mut self, c: usize, team: Team) -> Result<(), ()> {
-        if self.terminal.is_some() {
-            Err(())
+// Parses either the emoji rendering produced by `Display` (the result line is
+// ignored and recomputed) or the compact form: rows top to bottom separated by
+// `/`, `m` and `c` for discs, digits for runs of empty cells, followed by an
+// optional side to move, connect length and variant, e.g. `4/4/m3/mc2 m 4`.
+impl FromStr for Board {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains(WALL) {
+            Board::parse_emoji(s)
         } else {
-            let mut i = 3;
-            let mut placed = false;
-            while !placed {
-                if self.cells[i][c].is_none() {
-                    self.cells[i][c] = Some(team);
-                    placed = true;
+            Board::parse_compact(s)
+        }
+    }
+}
+
+impl Board {
+    fn parse_emoji(s: &str) -> Result<Self, String> {
+        let mut rows = Vec::new();
+        for line in s.lines().map(str::trim) {
+            let chars = line.chars().collect::<Vec<_>>();
+            if chars.len() >= 3 && chars.iter().all(|c| *c == WALL) {
+                return if rows
+                    .first()
+                    .is_some_and(|row: &Vec<_>| row.len() + 2 == chars.len())
+                {
+                    Board::from_rows(rows, 4, None, Variant::Standard)
                 } else {
-                    i -= 1;
-                }
+                    Err("Bottom wall doesn't match the board width".to_string())
+                };
             }
-            if placed {
-                self.check_terminal();
-                Ok(())
-            } else {
-                Err(())
+            match chars.as_slice() {
+                [WALL, cells @ .., WALL] => rows.push(
+                    cells
+                        .iter()
+                        .map(|c| match *c {
+                            MILK => Ok(Some(Team::Milk)),
+                            COOKIE => Ok(Some(Team::Cookie)),
+                            EMPTY => Ok(None),
+                            _ => Err(format!("Unexpected cell: {}", c)),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?,
+                ),
+                _ => return Err(format!("Unexpected line: {}", line)),
             }
         }
+        Err("Missing bottom wall".to_string())
+    }
+
+    fn parse_compact(s: &str) -> Result<Self, String> {
+        let mut fields = s.split_whitespace();
+        let rows = fields
+            .next()
+            .ok_or("Empty board")?
+            .split('/')
+            .map(|row| {
+                let mut cells = Vec::new();
+                let mut run = String::new();
+                // Runs are checked against MAX_SIZE before anything is
+                // allocated for them.
+                let flush = |run: &mut String, cells: &mut Vec<Option<Team>>| {
+                    if !run.is_empty() {
+                        let empty = run.parse::<usize>().map_err(|e| e.to_string())?;
+                        if empty > MAX_SIZE - cells.len() {
+                            return Err("Row too long".to_string());
+                        }
+                        cells.extend(vec![None; empty]);
+                        run.clear();
+                    }
+                    Ok(())
+                };
+                for c in row.chars() {
+                    if c.is_ascii_digit() {
+                        run.push(c);
+                        continue;
+                    }
+                    flush(&mut run, &mut cells)?;
+                    match c {
+                        'm' => cells.push(Some(Team::Milk)),
+                        'c' => cells.push(Some(Team::Cookie)),
+                        _ => return Err(format!("Unexpected cell: {}", c)),
+                    }
+                    if cells.len() > MAX_SIZE {
+                        return Err("Row too long".to_string());
+                    }
+                }
+                flush(&mut run, &mut cells)?;
+                Ok(cells)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let turn = match fields.next() {
+            Some("m") => Some(Team::Milk),
+            Some("c") => Some(Team::Cookie),
+            Some("-") | None => None,
+            Some(turn) => return Err(format!("Unexpected side to move: {}", turn)),
+        };
+        let connect = match fields.next() {
+            Some(connect) => connect.parse::<usize>().map_err(|e| e.to_string())?,
+            None => 4,
+        };
+        let variant = match fields.next() {
+            Some(variant) => variant
+                .parse::<Variant>()
+                .map_err(|_| format!("Unexpected variant: {}", variant))?,
+            None => Variant::Standard,
+        };
+        Board::from_rows(rows, connect, turn, variant)
     }
 
-    fn check_four(
-        a: Option<Team>,
-        b: Option<Team>,
-        c: Option<Team>,
-        d: Option<Team>,
-    ) -> Option<Terminal> {
-        if a == b && b == c && c == d {
-            a.map(Terminal::Win)
+    fn from_rows(
+        rows: Vec<Vec<Option<Team>>>,
+        connect: usize,
+        turn: Option<Team>,
+        variant: Variant,
+    ) -> Result<Self, String> {
+        let width = rows.first().map_or(0, Vec::len);
+        if rows.iter().any(|row| row.len() != width) {
+            return Err("Rows differ in width".to_string());
+        }
+        let mut board = Board::new(width, rows.len(), connect).ok_or("Invalid board size")?;
+        board.cells = rows;
+        board.variant = variant;
+        let floating = variant != Variant::GravityFree
+            && (0..board.width).any(|column| {
+                (1..board.height).any(|row| {
+                    board.cells[row - 1][column].is_some() && board.cells[row][column].is_none()
+                })
+            });
+        if floating {
+            return Err("Discs must rest on the bottom or another disc".to_string());
+        }
+        board.first = turn;
+        board.check_terminal();
+        Ok(board)
+    }
+
+    fn set_connect(&mut self, connect: usize) -> bool {
+        if (2..=self.width.max(self.height)).contains(&connect) {
+            self.connect = connect;
+            self.check_terminal();
+            true
         } else {
-            None
+            false
         }
     }
 
-    fn check_terminal(&mut self) {
-        let check_rows = (0..4).find_map(|row| {
-            Self::check_four(
-                self.cells[row][0],
-                self.cells[row][1],
-                self.cells[row][2],
-                self.cells[row][3],
-            )
-        });
-        let check_columns = (0..4).find_map(|column| {
-            Self::check_four(
-                self.cells[0][column],
-                self.cells[1][column],
-                self.cells[2][column],
-                self.cells[3][column],
-            )
-        });
-        let check_diagonal1 = Self::check_four(
-            self.cells[0][0],
-            self.cells[1][1],
-            self.cells[2][2],
-            self.cells[3][3],
-        );
-        let check_diagonal2 = Self::check_four(
-            self.cells[0][3],
-            self.cells[1][2],
-            self.cells[2][1],
-            self.cells[3][0],
-        );
-        let check_filled = if self
+    // The enforced next team, otherwise whichever team has fewer discs.
+    fn side_to_move(&self) -> Team {
+        self.next_team().unwrap_or_else(|| {
+            let (milk, cookie) =
+                self.cells
+                    .iter()
+                    .flatten()
+                    .fold((0, 0), |(milk, cookie), cell| match cell {
+                        Some(Team::Milk) => (milk + 1, cookie),
+                        Some(Team::Cookie) => (milk, cookie + 1),
+                        None => (milk, cookie),
+                    });
+            if cookie < milk {
+                Team::Cookie
+            } else {
+                Team::Milk
+            }
+        })
+    }
+
+    // Columns a disc can be dropped into.
+    fn legal_columns(&self) -> Vec<usize> {
+        self.legal_actions()
+            .into_iter()
+            .filter_map(|action| match action {
+                Action::Drop(column) => Some(column),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn legal_actions(&self) -> Vec<Action> {
+        match self.terminal {
+            Some(_) => Vec::new(),
+            None => self.actions(self.side_to_move()),
+        }
+    }
+
+    // Every move the variant allows `team` on the current grid.
+    fn actions(&self, team: Team) -> Vec<Action> {
+        let empty = |row: usize, column: usize| self.cells[row][column].is_none();
+        match self.variant {
+            Variant::Standard | Variant::Misere => (0..self.width)
+                .filter(|column| empty(0, *column))
+                .map(Action::Drop)
+                .collect(),
+            Variant::PopOut => {
+                let drops = (0..self.width)
+                    .filter(|column| empty(0, *column))
+                    .map(Action::Drop);
+                let pops = (0..self.width)
+                    .filter(|column| self.cells[self.height - 1][*column] == Some(team))
+                    .map(Action::Pop);
+                drops.chain(pops).collect()
+            }
+            Variant::GravityFree => (0..self.height)
+                .flat_map(|row| (0..self.width).map(move |column| (row, column)))
+                .filter(|(row, column)| empty(*row, *column))
+                .map(|(row, column)| Action::Put(row, column))
+                .collect(),
+        }
+    }
+
+    fn to_compact(&self) -> String {
+        let rows = self
             .cells
             .iter()
-            .all(|column| column.iter().all(Option::is_some))
+            .map(|row| {
+                let mut compact = String::new();
+                let mut run = 0;
+                for cell in row {
+                    match cell {
+                        None => run += 1,
+                        Some(team) => {
+                            if run > 0 {
+                                compact.push_str(&run.to_string());
+                                run = 0;
+                            }
+                            compact.push(match team {
+                                Team::Milk => 'm',
+                                Team::Cookie => 'c',
+                            });
+                        }
+                    }
+                }
+                if run > 0 {
+                    compact.push_str(&run.to_string());
+                }
+                compact
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        let turn = match self.next_team() {
+            Some(Team::Milk) => "m",
+            Some(Team::Cookie) => "c",
+            None => "-",
+        };
+        match self.variant {
+            Variant::Standard => format!("{} {} {}", rows, turn, self.connect),
+            variant => format!("{} {} {} {}", rows, turn, self.connect, variant.name()),
+        }
+    }
+
+    fn result(&self) -> Option<&'static str> {
+        self.terminal.as_ref().map(|terminal| match terminal {
+            Terminal::Win(team) => team.name(),
+            Terminal::Draw => "draw",
+            Terminal::Both => "invalid",
+        })
+    }
+
+    fn empty_cells(&self) -> usize {
+        self.cells
+            .iter()
+            .flatten()
+            .filter(|cell| cell.is_none())
+            .count()
+    }
+
+    fn new(width: usize, height: usize, connect: usize) -> Option<Self> {
+        if (1..=MAX_SIZE).contains(&width)
+            && (1..=MAX_SIZE).contains(&height)
+            && (2..=width.max(height)).contains(&connect)
         {
-            Some(Terminal::Draw)
+            Some(Board {
+                width,
+                height,
+                connect,
+                cells: vec![vec![None; width]; height],
+                variant: Variant::Standard,
+                terminal: None,
+                winning_cells: Vec::new(),
+                first: None,
+                moves: Vec::new(),
+                round: 0,
+            })
         } else {
             None
+        }
+    }
+
+    fn cleared(&self) -> Board {
+        let mut board = Board::new(self.width, self.height, self.connect).unwrap();
+        board.first = self.first;
+        board.variant = self.variant;
+        board.round = self.round + 1;
+        board
+    }
+
+    fn next_team(&self) -> Option<Team> {
+        self.first.map(|first| {
+            self.moves
+                .last()
+                .map_or(first, |last_move| last_move.team.other())
+        })
+    }
+
+    fn check_turn(&self, team: Team) -> Result<(), PlaceError> {
+        if self.terminal.is_some() {
+            Err(PlaceError::GameOver)
+        } else if self.next_team().is_some_and(|next| next != team) {
+            Err(PlaceError::WrongTurn)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn apply(&mut self, action: Action, team: Team) -> Result<(), PlaceError> {
+        self.check_turn(team)?;
+        let in_range = match action {
+            Action::Drop(column) | Action::Pop(column) => column < self.width,
+            Action::Put(row, column) => row < self.height && column < self.width,
         };
+        if !in_range {
+            return Err(PlaceError::OutOfRange);
+        }
+        if !self.actions(team).contains(&action) {
+            return Err(match action {
+                Action::Drop(_) if self.variant != Variant::GravityFree => PlaceError::ColumnFull,
+                _ => PlaceError::Illegal,
+            });
+        }
+        let (row, column) = match action {
+            Action::Drop(column) => {
+                let row = (0..self.height)
+                    .rev()
+                    .find(|&row| self.cells[row][column].is_none())
+                    .unwrap();
+                self.cells[row][column] = Some(team);
+                (row, column)
+            }
+            Action::Put(row, column) => {
+                self.cells[row][column] = Some(team);
+                (row, column)
+            }
+            Action::Pop(column) => {
+                for row in (1..self.height).rev() {
+                    self.cells[row][column] = self.cells[row - 1][column];
+                }
+                self.cells[0][column] = None;
+                (self.height - 1, column)
+            }
+        };
+        self.moves.push(Move {
+            team,
+            action,
+            row,
+            column,
+        });
+        self.check_terminal();
+        Ok(())
+    }
 
-        self.terminal = check_columns
-            .or(check_rows)
-            .or(check_diagonal1)
-            .or(check_diagonal2)
-            .or(check_filled);
+    fn undo(&mut self) -> Option<Move> {
+        let last_move = self.moves.pop()?;
+        let (row, column) = (last_move.row, last_move.column);
+        match last_move.action {
+            Action::Drop(_) | Action::Put(..) => self.cells[row][column] = None,
+            Action::Pop(_) => {
+                for row in 0..self.height - 1 {
+                    self.cells[row][column] = self.cells[row + 1][column];
+                }
+                self.cells[row][column] = Some(last_move.team);
+            }
+        }
+        self.check_terminal();
+        Some(last_move)
     }
 
-    fn random(seed: &mut StdRng) -> Self {
-        let mut board = Board::default();
-        for r in 0..4 {
-            for c in 0..4 {
-                board.cells[r][c] = if seed.gen::<bool>() {
+    // Moves as the team symbol followed by the 1-based column, e.g. `🥛4 🍪4 🥛3`.
+    // Pops are prefixed with `p`, placements without gravity give `row:column`.
+    fn history(&self) -> String {
+        self.moves
+            .iter()
+            .map(|m| match m.action {
+                Action::Drop(column) => format!("{}{}", m.team.symbol(), column + 1),
+                Action::Pop(column) => format!("{}p{}", m.team.symbol(), column + 1),
+                Action::Put(row, column) => {
+                    format!("{}{}:{}", m.team.symbol(), row + 1, column + 1)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn cell(&self, row: isize, column: isize) -> Option<Team> {
+        if row < 0 || column < 0 {
+            None
+        } else {
+            self.cells
+                .get(row as usize)
+                .and_then(|cells| cells.get(column as usize))
+                .copied()
+                .flatten()
+        }
+    }
+
+    // Scans for `connect` equal cells in every direction from every cell,
+    // returning each line's team and the cells it covers.
+    fn winning_lines(&self) -> Vec<(Team, Vec<(usize, usize)>)> {
+        DIRECTIONS
+            .iter()
+            .flat_map(|direction| {
+                (0..self.height as isize).flat_map(move |row| {
+                    (0..self.width as isize).map(move |column| ((row, column), direction))
+                })
+            })
+            .filter_map(|((row, column), (dr, dc))| {
+                let team = self.cell(row, column)?;
+                (1..self.connect as isize)
+                    .all(|i| self.cell(row + i * dr, column + i * dc) == Some(team))
+                    .then(|| {
+                        let cells = (0..self.connect as isize)
+                            .map(|i| ((row + i * dr) as usize, (column + i * dc) as usize))
+                            .collect();
+                        (team, cells)
+                    })
+            })
+            .collect()
+    }
+
+    // In misère completing a line loses. A PopOut pop can complete lines for
+    // both teams, in which case the team that popped wins.
+    fn check_terminal(&mut self) {
+        let lines = self.winning_lines();
+        let lines_for = |team: Team| lines.iter().any(|(owner, _)| *owner == team);
+        let winner = |team: Team| match self.variant {
+            Variant::Misere => lines_for(team.other()),
+            _ => lines_for(team),
+        };
+        let popped = self
+            .moves
+            .last()
+            .filter(|last_move| matches!(last_move.action, Action::Pop(_)))
+            .map(|last_move| last_move.team);
+        let stuck = self.actions(self.side_to_move()).is_empty();
+
+        self.terminal = match (winner(Team::Milk), winner(Team::Cookie), popped) {
+            (true, true, Some(team)) => Some(Terminal::Win(team)),
+            (true, true, None) => Some(Terminal::Both),
+            (true, false, _) => Some(Terminal::Win(Team::Milk)),
+            (false, true, _) => Some(Terminal::Win(Team::Cookie)),
+            (false, false, _) if stuck => Some(Terminal::Draw),
+            (false, false, _) => None,
+        };
+        self.winning_cells = lines
+            .into_iter()
+            .flat_map(|(_, cells)| cells)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+    }
+
+    // Fills every cell of an empty board of the same shape at random.
+    fn random(template: &Board, rng: &mut StdRng) -> Self {
+        let mut board = template.cleared();
+        for row in board.cells.iter_mut() {
+            for cell in row.iter_mut() {
+                *cell = if rng.gen::<bool>() {
                     Some(Team::Cookie)
                 } else {
                     Some(Team::Milk)
@@ -153,65 +696,973 @@ impl Board {
         board.check_terminal();
         board
     }
+
+    // Plays a random number of random legal moves from an empty board, so the
+    // position is reachable: discs obey gravity and turns alternate.
+    fn random_legal(template: &Board, rng: &mut StdRng) -> Self {
+        let mut board = template.cleared();
+        let plies = rng.gen_range(0..=board.width * board.height);
+        for _ in 0..plies {
+            let actions = board.legal_actions();
+            if actions.is_empty() {
+                break;
+            }
+            let action = actions[rng.gen_range(0..actions.len())];
+            let team = board.side_to_move();
+            board.apply(action, team).unwrap();
+        }
+        board
+    }
+}
+
+#[derive(Default, Serialize)]
+struct RandomStats {
+    count: usize,
+    milk: usize,
+    cookie: usize,
+    both: usize,
+    draw: usize,
+    ongoing: usize,
+    average_discs: f64,
+}
+
+impl RandomStats {
+    fn new(boards: &[Board]) -> Self {
+        let mut stats = RandomStats {
+            count: boards.len(),
+            ..Default::default()
+        };
+        let mut discs = 0;
+        for board in boards {
+            match board.terminal {
+                Some(Terminal::Win(Team::Milk)) => stats.milk += 1,
+                Some(Terminal::Win(Team::Cookie)) => stats.cookie += 1,
+                Some(Terminal::Both) => stats.both += 1,
+                Some(Terminal::Draw) => stats.draw += 1,
+                None => stats.ongoing += 1,
+            }
+            discs += board.width * board.height - board.empty_cells();
+        }
+        stats.average_discs = discs as f64 / boards.len() as f64;
+        stats
+    }
+}
+
+const DEFAULT_GAME: Uuid = Uuid::nil();
+
+pub struct Config {
+    pub idle_timeout: Duration,
+    pub pool: Option<PgPool>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            idle_timeout: Duration::from_secs(3600),
+            pool: None,
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+struct Players {
+    milk: Option<String>,
+    cookie: Option<String>,
+}
+
+struct Game {
+    board: Board,
+    // Compact form of the position the recorded moves start from.
+    start: String,
+    players: Players,
+    version: i64,
+    last_active: Instant,
+    updates: broadcast::Sender<Board>,
+}
+
+impl Game {
+    fn new(board: Board, players: Players) -> Self {
+        Game {
+            start: board.to_compact(),
+            board,
+            players,
+            version: 0,
+            last_active: Instant::now(),
+            updates: broadcast::channel(16).0,
+        }
+    }
+}
+
+impl Default for Game {
+    fn default() -> Self {
+        Game::new(Board::default(), Players::default())
+    }
 }
 
 pub struct State {
-    board: Mutex<Board>,
+    games: Mutex<HashMap<Uuid, Game>>,
     seed: Mutex<StdRng>,
+    idle_timeout: Duration,
+    pool: Option<PgPool>,
 }
 
 impl Default for State {
     fn default() -> Self {
+        State::new(Config::default())
+    }
+}
+
+impl State {
+    pub fn new(config: Config) -> Self {
         State {
-            board: Mutex::new(Board::default()),
+            games: Mutex::new(HashMap::from([(DEFAULT_GAME, Game::default())])),
             seed: Mutex::new(StdRng::seed_from_u64(2024)),
+            idle_timeout: config.idle_timeout,
+            pool: config.pool,
         }
     }
+
+    // Reloads unfinished games from Postgres by replaying their moves. The
+    // default game always exists, so when it isn't reloaded it picks up its
+    // stored version (for saves to go through) and starts a new round.
+    pub async fn restore(&self) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        let mut restored_default = false;
+        for (stored, moves) in store::active_games(pool, self.idle_timeout).await {
+            let Ok(mut board) = stored.start.parse::<Board>() else {
+                continue;
+            };
+            board.round = stored.round;
+            let replayed = moves.iter().all(|stored_move| {
+                stored_move
+                    .decode()
+                    .is_some_and(|(team, action)| board.apply(action, team).is_ok())
+            });
+            if replayed {
+                let players = Players {
+                    milk: stored.milk_player,
+                    cookie: stored.cookie_player,
+                };
+                let mut game = Game::new(board, players);
+                game.start = stored.start;
+                game.version = stored.version;
+                restored_default |= stored.id == DEFAULT_GAME;
+                self.games.lock().unwrap().insert(stored.id, game);
+            }
+        }
+        if !restored_default {
+            if let Some((version, round)) = store::counters(pool, DEFAULT_GAME).await {
+                let mut games = self.games.lock().unwrap();
+                let game = games.entry(DEFAULT_GAME).or_default();
+                game.version = version;
+                game.board.round = round + 1;
+            }
+        }
+    }
+
+    async fn persist(&self, id: Uuid) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        let snapshot = self
+            .games
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|game| store::Snapshot {
+                id,
+                start: game.start.clone(),
+                moves: game
+                    .board
+                    .moves
+                    .iter()
+                    .map(|m| (m.team, m.action))
+                    .collect(),
+                result: game.board.result(),
+                milk_player: game.players.milk.clone(),
+                cookie_player: game.players.cookie.clone(),
+                version: game.version,
+                round: game.board.round,
+            });
+        if let Some(snapshot) = snapshot {
+            store::save(pool, snapshot).await;
+        }
+    }
+
+    fn create_game(&self, board: Board, players: Players) -> Uuid {
+        let mut games = self.games.lock().unwrap();
+        self.expire(&mut games);
+        let id = Uuid::new_v4();
+        games.insert(id, Game::new(board, players));
+        id
+    }
+
+    fn with_game<T>(&self, id: Uuid, f: impl FnOnce(&mut Board) -> T) -> Option<T> {
+        let mut games = self.games.lock().unwrap();
+        self.expire(&mut games);
+        games.get_mut(&id).map(|game| {
+            game.last_active = Instant::now();
+            f(&mut game.board)
+        })
+    }
+
+    // A copy of the board and its version, for work done outside the lock.
+    fn snapshot(&self, id: Uuid) -> Option<(Board, i64)> {
+        let mut games = self.games.lock().unwrap();
+        self.expire(&mut games);
+        games.get_mut(&id).map(|game| {
+            game.last_active = Instant::now();
+            (game.board.clone(), game.version)
+        })
+    }
+
+    // Like `with_game`, but when the operation succeeds bumps the version
+    // used for persistence and publishes the board to stream subscribers.
+    fn update_game(
+        &self,
+        id: Uuid,
+        f: impl FnOnce(&mut Board) -> (StatusCode, String),
+    ) -> Option<(StatusCode, String)> {
+        self.update_game_at(id, None, f)
+    }
+
+    // Runs `f` only if the game is still at `version` (when given), so moves
+    // chosen from a snapshot aren't applied to a board that has since changed.
+    fn update_game_at(
+        &self,
+        id: Uuid,
+        version: Option<i64>,
+        f: impl FnOnce(&mut Board) -> (StatusCode, String),
+    ) -> Option<(StatusCode, String)> {
+        let mut games = self.games.lock().unwrap();
+        self.expire(&mut games);
+        games.get_mut(&id).map(|game| {
+            game.last_active = Instant::now();
+            if version.is_some_and(|version| version != game.version) {
+                return (StatusCode::CONFLICT, "".to_string());
+            }
+            let response = f(&mut game.board);
+            if response.0.is_success() {
+                if game.board.moves.is_empty() {
+                    game.start = game.board.to_compact();
+                }
+                game.version += 1;
+                let _ = game.updates.send(game.board.clone());
+            }
+            response
+        })
+    }
+
+    fn subscribe(&self, id: Uuid) -> Option<(Board, broadcast::Receiver<Board>)> {
+        let mut games = self.games.lock().unwrap();
+        self.expire(&mut games);
+        games
+            .get(&id)
+            .map(|game| (game.board.clone(), game.updates.subscribe()))
+    }
+
+    fn expire(&self, games: &mut HashMap<Uuid, Game>) {
+        games.retain(|id, game| {
+            *id == DEFAULT_GAME || game.last_active.elapsed() < self.idle_timeout
+        });
+    }
 }
 
-pub async fn board(state: Arc<State>, Path(op): Path<String>) -> (StatusCode, String) {
-    match op.as_str() {
-        "board" => {
-            let board = state.board.lock().unwrap();
-            (StatusCode::OK, format!("{}", board))
+#[derive(Serialize)]
+struct BoardView<'a> {
+    width: usize,
+    height: usize,
+    connect: usize,
+    variant: Variant,
+    cells: &'a [Vec<Option<Team>>],
+    terminal: Option<&'a Terminal>,
+    winning_cells: &'a [(usize, usize)],
+    turn: Option<Team>,
+    legal_columns: Vec<usize>,
+    legal_pops: Vec<usize>,
+    compact: String,
+}
+
+impl<'a> From<&'a Board> for BoardView<'a> {
+    fn from(board: &'a Board) -> Self {
+        BoardView {
+            width: board.width,
+            height: board.height,
+            connect: board.connect,
+            variant: board.variant,
+            cells: &board.cells,
+            terminal: board.terminal.as_ref(),
+            winning_cells: &board.winning_cells,
+            turn: board.terminal.is_none().then(|| board.side_to_move()),
+            legal_columns: board
+                .legal_columns()
+                .into_iter()
+                .map(|column| column + 1)
+                .collect(),
+            legal_pops: board
+                .legal_actions()
+                .into_iter()
+                .filter_map(|action| match action {
+                    Action::Pop(column) => Some(column + 1),
+                    _ => None,
+                })
+                .collect(),
+            compact: board.to_compact(),
         }
-        "random-board" => {
-            let mut seed = state.seed.lock().unwrap();
-            (StatusCode::OK, format!("{}", Board::random(&mut seed)))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Json,
+    Html,
+}
+
+// htmx requests get the HTML fragment, `Accept: application/json` gets JSON,
+// and everything else the emoji rendering.
+fn response_format(headers: &HeaderMap) -> Format {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    if header("hx-request") == Some("true") {
+        Format::Html
+    } else if header(ACCEPT.as_str()).is_some_and(|value| value.contains("application/json")) {
+        Format::Json
+    } else {
+        Format::Text
+    }
+}
+
+#[derive(Template)]
+#[template(path = "board.html")]
+struct BoardTemplate {
+    columns: Vec<ColumnView>,
+    pops: bool,
+    team: &'static str,
+    turn: Option<char>,
+    banner: Option<&'static str>,
+}
+
+// Flags on columns and cells say which moves are legal, so the template only
+// wires up requests the current variant accepts.
+struct ColumnView {
+    number: usize,
+    playable: bool,
+    pop: bool,
+    cells: Vec<CellView>,
+}
+
+struct CellView {
+    number: usize,
+    symbol: char,
+    win: bool,
+    put: bool,
+}
+
+impl From<&Board> for BoardTemplate {
+    fn from(board: &Board) -> Self {
+        let legal = board.legal_actions();
+        let columns = (0..board.width)
+            .map(|column| ColumnView {
+                number: column + 1,
+                playable: legal.contains(&Action::Drop(column)),
+                pop: legal.contains(&Action::Pop(column)),
+                cells: (0..board.height)
+                    .map(|row| CellView {
+                        number: row + 1,
+                        symbol: board.cells[row][column].map_or(EMPTY, Team::symbol),
+                        win: board.winning_cells.contains(&(row, column)),
+                        put: legal.contains(&Action::Put(row, column)),
+                    })
+                    .collect(),
+            })
+            .collect();
+        let turn = board.terminal.is_none().then(|| board.side_to_move());
+        BoardTemplate {
+            columns,
+            pops: board.variant == Variant::PopOut,
+            team: board.side_to_move().name(),
+            turn: turn.map(Team::symbol),
+            banner: board.terminal.as_ref().map(Terminal::message),
         }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "play.html")]
+struct PlayTemplate {
+    board: String,
+}
+
+fn render(board: &Board, format: Format) -> String {
+    match format {
+        Format::Text => format!("{}", board),
+        Format::Json => serde_json::to_string(&BoardView::from(board)).unwrap(),
+        Format::Html => BoardTemplate::from(board).render().unwrap(),
+    }
+}
+
+fn respond(board: &Board, result: Result<(), PlaceError>, format: Format) -> (StatusCode, String) {
+    match result {
+        Ok(_) => (StatusCode::OK, render(board, format)),
+        Err(PlaceError::OutOfRange) => (StatusCode::BAD_REQUEST, "".to_string()),
+        Err(error) => (error.status(), render(board, format)),
+    }
+}
+
+// Streams the rendered board (`format=json` or `format=html`) as Server-Sent Events,
+// starting with the current position and following every change to the game.
+pub async fn stream(
+    state: Arc<State>,
+    id: Option<Uuid>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    let (board, updates) = state
+        .subscribe(id.unwrap_or(DEFAULT_GAME))
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let format = match params.get("format").map(String::as_str) {
+        Some("json") => Format::Json,
+        Some("html") => Format::Html,
+        _ => Format::Text,
+    };
+    let events = tokio_stream::once(board)
+        .chain(BroadcastStream::new(updates).filter_map(Result::ok))
+        .map(move |board| Ok(Event::default().data(render(&board, format))));
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+// Serves a page for playing the game in the browser. Board fragments post
+// moves relative to the page URL, so the same markup works for any game.
+pub async fn play(state: Arc<State>, id: Option<Uuid>) -> Result<Html<String>, StatusCode> {
+    let board = state
+        .with_game(id.unwrap_or(DEFAULT_GAME), |board| {
+            render(board, Format::Html)
+        })
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Html(PlayTemplate { board }.render().unwrap()))
+}
+
+// Splits `games/:id/rest` into the game id and the remaining operation,
+// mapping unscoped operations to the default game.
+fn scope(op: &str) -> Option<(Uuid, &str)> {
+    match op.strip_prefix("games/") {
+        Some(rest) => rest
+            .split_once('/')
+            .and_then(|(id, rest)| Uuid::parse_str(id).ok().map(|id| (id, rest))),
+        None => Some((DEFAULT_GAME, op)),
+    }
+}
+
+pub async fn board(
+    state: Arc<State>,
+    Path(op): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> (StatusCode, String) {
+    let format = response_format(&headers);
+    match scope(&op) {
+        Some((id, "board")) => match state.with_game(id, |board| render(board, format)) {
+            Some(board) => (StatusCode::OK, board),
+            None => (StatusCode::NOT_FOUND, "".to_string()),
+        },
+        Some((id, "history")) => match state.with_game(id, |board| board.history()) {
+            Some(history) => (StatusCode::OK, format!("{}\n", history)),
+            None => (StatusCode::NOT_FOUND, "".to_string()),
+        },
+        Some((DEFAULT_GAME, "leaderboard")) => match &state.pool {
+            Some(pool) => {
+                let standings = store::leaderboard(pool).await;
+                (StatusCode::OK, serde_json::to_string(&standings).unwrap())
+            }
+            None => (StatusCode::NOT_FOUND, "".to_string()),
+        },
+        Some((DEFAULT_GAME, "random-board")) => match random_boards(&params) {
+            Some(request) => {
+                let state = state.clone();
+                let boards = tokio::task::spawn_blocking(move || request.generate(&state.seed))
+                    .await
+                    .unwrap();
+                random_response(&boards, &params, format)
+            }
+            None => (StatusCode::BAD_REQUEST, "".to_string()),
+        },
         _ => (StatusCode::NOT_FOUND, "".to_string()),
     }
 }
 
-pub async fn game(state: Arc<State>, Path(op): Path<String>) -> (StatusCode, String) {
-    match op.as_str() {
-        "reset" => {
-            let mut board = state.board.lock().unwrap();
-            let mut seed = state.seed.lock().unwrap();
-            *board = Board::default();
-            *seed = StdRng::seed_from_u64(2024);
-            (StatusCode::OK, format!("{}", board))
+// Renders generated boards as summary stats (`stats=true`), a single board,
+// or a list in the requested format.
+fn random_response(
+    boards: &[Board],
+    params: &HashMap<String, String>,
+    format: Format,
+) -> (StatusCode, String) {
+    if params.get("stats").is_some_and(|stats| stats == "true") {
+        let stats = RandomStats::new(boards);
+        (StatusCode::OK, serde_json::to_string(&stats).unwrap())
+    } else if boards.len() == 1 {
+        (StatusCode::OK, render(&boards[0], format))
+    } else if format == Format::Json {
+        let views = boards.iter().map(BoardView::from).collect::<Vec<_>>();
+        (StatusCode::OK, serde_json::to_string(&views).unwrap())
+    } else {
+        let boards = boards.iter().map(|board| render(board, format));
+        (StatusCode::OK, boards.collect::<Vec<_>>().join("\n"))
+    }
+}
+
+pub async fn game(
+    state: Arc<State>,
+    Path(op): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: String,
+) -> (StatusCode, String) {
+    let format = response_format(&headers);
+    if op == "games" {
+        return match (new_board(&params), players(&params)) {
+            (Some(board), Some(players)) => {
+                let id = state.create_game(board, players);
+                state.persist(id).await;
+                (StatusCode::CREATED, id.to_string())
+            }
+            _ => (StatusCode::BAD_REQUEST, "".to_string()),
+        };
+    }
+    if op == "tournament" {
+        return match tournament_params(&params) {
+            Some(tournament) => {
+                let standings = tokio::task::spawn_blocking(move || tournament.run())
+                    .await
+                    .unwrap();
+                (StatusCode::OK, serde_json::to_string(&standings).unwrap())
+            }
+            None => (StatusCode::BAD_REQUEST, "".to_string()),
+        };
+    }
+    let response = match scope(&op) {
+        Some((id, "reset")) => {
+            let reset = state.update_game(id, |board| {
+                *board = board.cleared();
+                (StatusCode::OK, render(board, format))
+            });
+            if id == DEFAULT_GAME {
+                let mut seed = state.seed.lock().unwrap();
+                *seed = StdRng::seed_from_u64(2024);
+            }
+            reset.unwrap_or((StatusCode::NOT_FOUND, "".to_string()))
         }
-        str if str.starts_with("place") => {
-            match str.strip_prefix("place/").unwrap().split_once('/') {
-                Some((team, column))
-                    if (team == "milk" || team == "cookie") && "1234".contains(column) =>
-                {
-                    let mut board = state.board.lock().unwrap();
-                    let team = if team == "milk" {
-                        Team::Milk
-                    } else {
-                        Team::Cookie
-                    };
-                    let column = column.parse::<usize>().unwrap() - 1;
-                    match board.place(column, team) {
-                        Ok(_) => (StatusCode::OK, format!("{}", board)),
-                        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, format!("{}", board)),
+        Some((id, "undo")) => {
+            let undone = state.update_game(id, |board| match board.undo() {
+                Some(_) => (StatusCode::OK, render(board, format)),
+                None => (StatusCode::CONFLICT, render(board, format)),
+            });
+            undone.unwrap_or((StatusCode::NOT_FOUND, "".to_string()))
+        }
+        Some((_, "analyze")) => {
+            let board = body
+                .parse::<Board>()
+                .ok()
+                .and_then(|mut board| match params.get("connect") {
+                    Some(connect) => connect
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|connect| board.set_connect(*connect))
+                        .map(|_| board),
+                    None => Some(board),
+                })
+                .filter(|board| matches!(board.variant, Variant::Standard | Variant::Misere));
+            let depth = match params.get("depth") {
+                Some(depth) => depth
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|depth| (1..=ai::MAX_DEPTH).contains(depth))
+                    .map(Some),
+                None => Some(None),
+            };
+            match (board, depth) {
+                (Some(mut board), Some(depth)) => {
+                    let analysis =
+                        tokio::task::spawn_blocking(move || ai::analyze(&mut board, depth))
+                            .await
+                            .unwrap();
+                    (StatusCode::OK, serde_json::to_string(&analysis).unwrap())
+                }
+                _ => (StatusCode::BAD_REQUEST, "".to_string()),
+            }
+        }
+        Some((id, str)) if str.starts_with("ai-move/") => {
+            match (
+                str.strip_prefix("ai-move/").unwrap().parse::<Team>(),
+                opponent(&params),
+            ) {
+                (Ok(team), Some(opponent)) => ai_move(&state, id, opponent, team, format)
+                    .await
+                    .unwrap_or((StatusCode::NOT_FOUND, "".to_string())),
+                _ => (StatusCode::BAD_REQUEST, "".to_string()),
+            }
+        }
+        Some((id, str))
+            if ["place/", "pop/", "put/"]
+                .iter()
+                .any(|prefix| str.starts_with(prefix)) =>
+        {
+            let target = parse_move(str);
+            // `reply=true` lets the server answer the move for the other team.
+            let reply = match params.get("reply").map(String::as_str) {
+                Some("true") => opponent(&params).map(Some),
+                Some("false") | None => Some(None),
+                _ => None,
+            };
+            match (target, reply) {
+                (Some((team, action)), Some(reply)) => {
+                    let placed = state.update_game(id, |board| {
+                        let result = action.and_then(|action| board.apply(action, team));
+                        respond(board, result, format)
+                    });
+                    match (placed, reply) {
+                        // The reply is left out if it can't be played, such as
+                        // when the move ended the game.
+                        (Some(placed), Some(opponent)) if placed.0.is_success() => {
+                            ai_move(&state, id, opponent, team.other(), format)
+                                .await
+                                .filter(|replied| replied.0.is_success())
+                                .unwrap_or(placed)
+                        }
+                        (placed, _) => placed.unwrap_or((StatusCode::NOT_FOUND, "".to_string())),
                     }
                 }
                 _ => (StatusCode::BAD_REQUEST, "".to_string()),
             }
         }
         _ => (StatusCode::NOT_FOUND, "".to_string()),
+    };
+    if let (Some((id, op)), true) = (scope(&op), response.0.is_success()) {
+        if changes_game(op) {
+            state.persist(id).await;
+        }
+    }
+    response
+}
+
+// Whether a scoped operation can change the game, and so needs saving.
+fn changes_game(op: &str) -> bool {
+    matches!(op, "reset" | "undo")
+        || ["ai-move/", "place/", "pop/", "put/"]
+            .iter()
+            .any(|prefix| op.starts_with(prefix))
+}
+
+// Searches a copy of the board on the blocking pool, then plays the move if
+// nobody else has moved in the meantime (409 otherwise).
+async fn ai_move(
+    state: &State,
+    id: Uuid,
+    mut opponent: ai::Opponent,
+    team: Team,
+    format: Format,
+) -> Option<(StatusCode, String)> {
+    let (board, version) = state.snapshot(id)?;
+    let action = tokio::task::spawn_blocking(move || opponent.choose(&board, team))
+        .await
+        .unwrap();
+    state.update_game_at(id, Some(version), |board| {
+        let result = action.and_then(|action| board.apply(action, team));
+        respond(board, result, format)
+    })
+}
+
+pub async fn load(
+    state: Arc<State>,
+    Path(op): Path<String>,
+    headers: HeaderMap,
+    body: String,
+) -> (StatusCode, String) {
+    let format = response_format(&headers);
+    match (scope(&op), body.parse::<Board>()) {
+        (Some((id, "board")), Ok(loaded)) => {
+            let loaded = state.update_game(id, |board| {
+                *board = Board {
+                    round: board.round + 1,
+                    ..loaded
+                };
+                (StatusCode::OK, render(board, format))
+            });
+            state.persist(id).await;
+            loaded.unwrap_or((StatusCode::NOT_FOUND, "".to_string()))
+        }
+        (Some((_, "board")), Err(_)) => (StatusCode::BAD_REQUEST, "".to_string()),
+        _ => (StatusCode::NOT_FOUND, "".to_string()),
+    }
+}
+
+// Builds the board for a new game from `width`, `height`, `connect`, and
+// `turns=strict` with an optional `first` team.
+fn new_board(params: &HashMap<String, String>) -> Option<Board> {
+    let param = |key: &str, default: usize| match params.get(key) {
+        Some(value) => value.parse::<usize>().ok(),
+        None => Some(default),
+    };
+    let mut board = Board::new(
+        param("width", 4)?,
+        param("height", 4)?,
+        param("connect", 4)?,
+    )?;
+    board.first = match params.get("turns").map(String::as_str) {
+        Some("strict") => match params.get("first") {
+            Some(team) => Some(team.parse().ok()?),
+            None => Some(Team::Milk),
+        },
+        Some("free") | None => None,
+        _ => return None,
+    };
+    if let Some(variant) = params.get("variant") {
+        board.variant = variant.parse().ok()?;
+    }
+    Some(board)
+}
+
+// Parses `place/:team/:column`, `pop/:team/:column` or `put/:team/:row/:column`
+// with 1-based coordinates, treating unparseable coordinates as out of range.
+fn parse_move(op: &str) -> Option<(Team, Result<Action, PlaceError>)> {
+    let mut parts = op.split('/');
+    let kind = parts.next()?;
+    let team = parts.next()?.parse::<Team>().ok()?;
+    let coordinates = parts
+        .map(|part| part.parse::<usize>().ok().and_then(|n| n.checked_sub(1)))
+        .collect::<Option<Vec<_>>>();
+    let action = match (kind, coordinates.as_deref()) {
+        ("place", Some(&[column])) => Ok(Action::Drop(column)),
+        ("pop", Some(&[column])) => Ok(Action::Pop(column)),
+        ("put", Some(&[row, column])) => Ok(Action::Put(row, column)),
+        _ => Err(PlaceError::OutOfRange),
+    };
+    Some((team, action))
+}
+
+struct RandomBoards {
+    template: Board,
+    count: usize,
+    generate: fn(&Board, &mut StdRng) -> Board,
+    seed: Option<u64>,
+}
+
+impl RandomBoards {
+    // Uses its own generator when seeded; otherwise advances the shared one,
+    // which is why this runs on a blocking thread rather than the runtime.
+    fn generate(&self, shared: &Mutex<StdRng>) -> Vec<Board> {
+        let boards = |rng: &mut StdRng| {
+            (0..self.count)
+                .map(|_| (self.generate)(&self.template, rng))
+                .collect()
+        };
+        match self.seed {
+            Some(seed) => boards(&mut StdRng::seed_from_u64(seed)),
+            None => boards(&mut shared.lock().unwrap()),
+        }
+    }
+}
+
+// Reads `count` boards shaped by `width`/`height`/`connect`, either fully
+// filled (`mode=fill`) or reachable by legal play (`mode=legal`). A `seed`
+// makes the set reproducible. Requests over `MAX_RANDOM_CELLS` are rejected.
+fn random_boards(params: &HashMap<String, String>) -> Option<RandomBoards> {
+    let template = new_board(params)?;
+    let count = match params.get("count") {
+        Some(count) => count
+            .parse::<usize>()
+            .ok()
+            .filter(|count| (1..=1000).contains(count))?,
+        None => 1,
+    };
+    let generate = match params.get("mode").map(String::as_str) {
+        Some("fill") | None => Board::random,
+        Some("legal") => Board::random_legal,
+        _ => return None,
+    };
+    let seed = match params.get("seed") {
+        Some(seed) => Some(seed.parse::<u64>().ok()?),
+        None => None,
+    };
+    Some(RandomBoards {
+        template,
+        count,
+        generate,
+        seed,
+    })
+    .filter(|request| {
+        request.count * request.template.width * request.template.height <= MAX_RANDOM_CELLS
+    })
+}
+
+// Reads the board shape, a comma separated list of `strategies`, the number
+// of `games` each pair plays and the `seed` for a tournament, rejecting
+// tournaments too large to run.
+fn tournament_params(params: &HashMap<String, String>) -> Option<tournament::Tournament> {
+    let template = new_board(params)?;
+    let strategies = params
+        .get("strategies")?
+        .split(',')
+        .map(|spec| ai::strategy(spec.trim(), tournament::MAX_DEPTH))
+        .collect::<Option<Vec<_>>>()
+        .filter(|strategies| (2..=8).contains(&strategies.len()))?;
+    let games = match params.get("games") {
+        Some(games) => games
+            .parse::<usize>()
+            .ok()
+            .filter(|games| (1..=100).contains(games))?,
+        None => 10,
+    };
+    let seed = match params.get("seed") {
+        Some(seed) => seed.parse::<u64>().ok()?,
+        None => 2024,
+    };
+    Some(tournament::Tournament {
+        template,
+        strategies,
+        games,
+        seed,
+    })
+    .filter(tournament::Tournament::within_limits)
+}
+
+// Optional player names for each team, used for the leaderboard.
+fn players(params: &HashMap<String, String>) -> Option<Players> {
+    let name = |team: &str| match params.get(team).map(|name| name.trim()) {
+        Some(name) if name.is_empty() || name.len() > 64 => Err(()),
+        Some(name) => Ok(Some(name.to_string())),
+        None => Ok(None),
+    };
+    Some(Players {
+        milk: name("milk").ok()?,
+        cookie: name("cookie").ok()?,
+    })
+}
+
+// Reads the search depth from `depth` or `difficulty`, and an optional `seed`
+// that makes tie-breaking between equally scored columns reproducible.
+fn opponent(params: &HashMap<String, String>) -> Option<ai::Opponent> {
+    let depth = match (
+        params.get("depth"),
+        params.get("difficulty").map(String::as_str),
+    ) {
+        (Some(depth), _) => depth
+            .parse::<u32>()
+            .ok()
+            .filter(|depth| (1..=ai::MAX_DEPTH).contains(depth))?,
+        (None, Some("easy")) => 2,
+        (None, Some("medium") | None) => 4,
+        (None, Some("hard")) => 8,
+        _ => return None,
+    };
+    let seed = match params.get("seed") {
+        Some(seed) => Some(seed.parse::<u64>().ok()?),
+        None => None,
+    };
+    Some(ai::Opponent::new(depth, seed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::seq::SliceRandom;
+
+    const VARIANTS: [Variant; 4] = [
+        Variant::Standard,
+        Variant::PopOut,
+        Variant::GravityFree,
+        Variant::Misere,
+    ];
+
+    fn random_board(rng: &mut StdRng) -> Board {
+        let width = rng.gen_range(2..=7);
+        let height = rng.gen_range(1..=7);
+        let connect = rng.gen_range(2..=width.max(height));
+        let mut board = Board::new(width, height, connect).unwrap();
+        board.variant = *VARIANTS.choose(rng).unwrap();
+        board.first = [None, Some(Team::Milk), Some(Team::Cookie)]
+            .choose(rng)
+            .copied()
+            .unwrap();
+        board
+    }
+
+    // A legal action half the time, otherwise any action at all, including
+    // ones off the board or by the wrong team.
+    fn random_move(board: &Board, rng: &mut StdRng) -> (Team, Action) {
+        let team = if rng.gen() { Team::Milk } else { Team::Cookie };
+        let legal = board.actions(team);
+        if !legal.is_empty() && rng.gen() {
+            return (team, *legal.choose(rng).unwrap());
+        }
+        let row = rng.gen_range(0..board.height + 2);
+        let column = rng.gen_range(0..board.width + 2);
+        let action = match rng.gen_range(0..3) {
+            0 => Action::Drop(column),
+            1 => Action::Pop(column),
+            _ => Action::Put(row, column),
+        };
+        (team, action)
+    }
+
+    // Plays random sequences, calling `check` with the board before each move.
+    fn play_random(check: impl Fn(&Board, Team, Action)) {
+        let mut rng = StdRng::seed_from_u64(2024);
+        for _ in 0..500 {
+            let mut board = random_board(&mut rng);
+            for _ in 0..60 {
+                let (team, action) = random_move(&board, &mut rng);
+                check(&board, team, action);
+                let _ = board.apply(action, team);
+            }
+        }
+    }
+
+    #[test]
+    fn apply_only_changes_the_board_when_it_succeeds() {
+        play_random(|board, team, action| {
+            let mut after = board.clone();
+            match after.apply(action, team) {
+                Ok(()) => assert_eq!(after.moves.len(), board.moves.len() + 1),
+                Err(_) => {
+                    assert!(after.cells == board.cells);
+                    assert_eq!(after.moves.len(), board.moves.len());
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn dropping_into_a_full_column_is_column_full() {
+        play_random(|board, team, _| {
+            if board.variant == Variant::GravityFree || board.check_turn(team).is_err() {
+                return;
+            }
+            for column in 0..board.width {
+                if board.cells[0][column].is_some() {
+                    let mut after = board.clone();
+                    let result = after.apply(Action::Drop(column), team);
+                    assert_eq!(result, Err(PlaceError::ColumnFull));
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn undo_restores_the_board() {
+        play_random(|board, team, action| {
+            let mut after = board.clone();
+            if after.apply(action, team).is_ok() {
+                assert!(after.undo().is_some());
+                assert!(after.cells == board.cells);
+                assert_eq!(after.to_compact(), board.to_compact());
+                assert_eq!(after.winning_cells, board.winning_cells);
+                assert_eq!(
+                    after.terminal.as_ref().map(Terminal::message),
+                    board.terminal.as_ref().map(Terminal::message)
+                );
+            }
+        });
     }
 }
@@ -0,0 +1,397 @@
+use super::{Action, Board, PlaceError, Team, Terminal, Variant, DIRECTIONS};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::collections::HashMap;
+
+pub const WIN: i32 = 1_000_000;
+pub const MAX_DEPTH: u32 = 16;
+const DEFAULT_DEPTH: u32 = 10;
+// Positions a full-width search may reach before pruning.
+const SEARCH_BUDGET: u64 = 10_000_000;
+
+enum Bound {
+    Exact,
+    Lower,
+    Upper,
+}
+
+struct Entry {
+    depth: u32,
+    value: i32,
+    bound: Bound,
+}
+
+// Negamax search with alpha-beta pruning and a transposition table keyed by
+// an incrementally updated Zobrist hash of the board.
+pub struct Search {
+    zobrist: Vec<[u64; 2]>,
+    side: u64,
+    hash: u64,
+    table: HashMap<u64, Entry>,
+}
+
+impl Search {
+    pub fn new(board: &Board) -> Self {
+        let mut rng = StdRng::seed_from_u64(2024);
+        let zobrist = (0..board.width * board.height)
+            .map(|_| [rng.gen(), rng.gen()])
+            .collect::<Vec<_>>();
+        let hash = board
+            .cells
+            .iter()
+            .flatten()
+            .zip(&zobrist)
+            .filter_map(|(cell, keys)| cell.map(|team| keys[index(team)]))
+            .fold(0, |hash, key| hash ^ key);
+        Search {
+            zobrist,
+            side: rng.gen(),
+            hash,
+            table: HashMap::new(),
+        }
+    }
+
+    // Score of every legal move for `team` to move.
+    pub fn scores(&mut self, board: &mut Board, team: Team, depth: u32) -> Vec<(Action, i32)> {
+        let depth = depth.max(1);
+        let mut scores = Vec::new();
+        for action in board.actions(team) {
+            if self.play(board, action, team) {
+                let value = -self.negamax(board, team.other(), depth - 1, -2 * WIN, 2 * WIN);
+                self.unplay(board);
+                scores.push((action, value));
+            }
+        }
+        scores
+    }
+
+    pub fn negamax(
+        &mut self,
+        board: &mut Board,
+        team: Team,
+        depth: u32,
+        mut alpha: i32,
+        mut beta: i32,
+    ) -> i32 {
+        match &board.terminal {
+            Some(Terminal::Draw | Terminal::Both) => return 0,
+            Some(Terminal::Win(winner)) if *winner == team => return WIN + depth as i32,
+            Some(Terminal::Win(_)) => return -WIN - depth as i32,
+            None if depth == 0 => return evaluate(board, team),
+            None => {}
+        }
+
+        let key = match team {
+            Team::Milk => self.hash,
+            Team::Cookie => self.hash ^ self.side,
+        };
+        let alpha_orig = alpha;
+        if let Some(entry) = self.table.get(&key).filter(|entry| entry.depth >= depth) {
+            match entry.bound {
+                Bound::Exact => return entry.value,
+                Bound::Lower => alpha = alpha.max(entry.value),
+                Bound::Upper => beta = beta.min(entry.value),
+            }
+            if alpha >= beta {
+                return entry.value;
+            }
+        }
+
+        let mut best = -2 * WIN;
+        for action in center_first(board, team) {
+            if self.play(board, action, team) {
+                let value = -self.negamax(board, team.other(), depth - 1, -beta, -alpha);
+                self.unplay(board);
+                best = best.max(value);
+                alpha = alpha.max(value);
+                if alpha >= beta {
+                    break;
+                }
+            }
+        }
+
+        let bound = if best <= alpha_orig {
+            Bound::Upper
+        } else if best >= beta {
+            Bound::Lower
+        } else {
+            Bound::Exact
+        };
+        self.table.insert(
+            key,
+            Entry {
+                depth,
+                value: best,
+                bound,
+            },
+        );
+        best
+    }
+
+    // Moves only change one column, so the hash is updated by swapping that
+    // column's keys before and after the move.
+    fn play(&mut self, board: &mut Board, action: Action, team: Team) -> bool {
+        let before = self.column_hash(board, action.column());
+        if board.apply(action, team).is_ok() {
+            self.hash ^= before ^ self.column_hash(board, action.column());
+            true
+        } else {
+            false
+        }
+    }
+
+    fn unplay(&mut self, board: &mut Board) {
+        if let Some(last_move) = board.moves.last() {
+            let column = last_move.column;
+            let before = self.column_hash(board, column);
+            board.undo();
+            self.hash ^= before ^ self.column_hash(board, column);
+        }
+    }
+
+    fn column_hash(&self, board: &Board, column: usize) -> u64 {
+        (0..board.height)
+            .filter_map(|row| {
+                board.cells[row][column]
+                    .map(|team| self.zobrist[row * board.width + column][index(team)])
+            })
+            .fold(0, |hash, key| hash ^ key)
+    }
+}
+
+// The deepest search on `board` that stays within `SEARCH_BUDGET`, so wide
+// boards and variants with many moves per turn get shallower searches.
+pub fn max_depth(board: &Board) -> u32 {
+    let branching = match board.variant {
+        Variant::GravityFree => board.width * board.height,
+        Variant::PopOut => 2 * board.width,
+        _ => board.width,
+    }
+    .max(2) as u64;
+    let mut depth = 1;
+    let mut positions = branching;
+    while depth < MAX_DEPTH && positions * branching <= SEARCH_BUDGET {
+        positions *= branching;
+        depth += 1;
+    }
+    depth
+}
+
+fn index(team: Team) -> usize {
+    match team {
+        Team::Milk => 0,
+        Team::Cookie => 1,
+    }
+}
+
+fn center_first(board: &Board, team: Team) -> Vec<Action> {
+    let width = board.width as isize;
+    let mut actions = board.actions(team);
+    actions.sort_by_key(|action| (2 * action.column() as isize - width + 1).abs());
+    actions
+}
+
+// Sums every window of `connect` cells that only one team occupies, weighted
+// by the square of how many cells that team holds. Lines are bad in misère.
+fn evaluate(board: &Board, team: Team) -> i32 {
+    match board.variant {
+        Variant::Misere => -windows(board, team),
+        _ => windows(board, team),
+    }
+}
+
+fn windows(board: &Board, team: Team) -> i32 {
+    let connect = board.connect as isize;
+    let mut score = 0;
+    for (dr, dc) in DIRECTIONS {
+        for row in 0..board.height as isize {
+            for column in 0..board.width as isize {
+                let end = (row + (connect - 1) * dr, column + (connect - 1) * dc);
+                if end.0 < 0
+                    || end.1 < 0
+                    || end.0 >= board.height as isize
+                    || end.1 >= board.width as isize
+                {
+                    continue;
+                }
+                let (own, other) = (0..connect).fold((0, 0), |(own, other), i| {
+                    match board.cell(row + i * dr, column + i * dc) {
+                        Some(cell) if cell == team => (own + 1, other),
+                        Some(_) => (own, other + 1),
+                        None => (own, other),
+                    }
+                });
+                if other == 0 {
+                    score += own * own;
+                } else if own == 0 {
+                    score -= other * other;
+                }
+            }
+        }
+    }
+    score
+}
+
+pub struct Opponent {
+    depth: u32,
+    rng: StdRng,
+}
+
+impl Opponent {
+    pub fn new(depth: u32, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Opponent { depth, rng }
+    }
+
+    // Searches without touching `board`, so the caller can do it on a copy
+    // and apply the move later.
+    pub fn choose(&mut self, board: &Board, team: Team) -> Result<Action, PlaceError> {
+        board.check_turn(team)?;
+        best_move(board, team, self.depth, &mut self.rng).ok_or(PlaceError::ColumnFull)
+    }
+}
+
+// Picks one of the best scoring moves, breaking ties with `rng`.
+pub fn best_move(board: &Board, team: Team, depth: u32, rng: &mut StdRng) -> Option<Action> {
+    let depth = depth.min(max_depth(board));
+    let mut board = board.clone();
+    let scores = Search::new(&board).scores(&mut board, team, depth);
+    let best = scores.iter().map(|(_, score)| *score).max()?;
+    let candidates = scores
+        .iter()
+        .filter(|(_, score)| *score == best)
+        .map(|(action, _)| *action)
+        .collect::<Vec<_>>();
+    candidates.choose(rng).copied()
+}
+
+// A way of choosing a column for `team` on a board that is not finished.
+pub trait Strategy: Send + Sync {
+    fn name(&self) -> String;
+    fn choose(&self, board: &Board, team: Team, rng: &mut StdRng) -> Option<Action>;
+}
+
+// Plays any legal move.
+pub struct Random;
+
+// Looks one move ahead: wins when it can, otherwise maximises the heuristic.
+pub struct Greedy;
+
+pub struct Minimax {
+    pub depth: u32,
+}
+
+impl Strategy for Random {
+    fn name(&self) -> String {
+        "random".to_string()
+    }
+
+    fn choose(&self, board: &Board, team: Team, rng: &mut StdRng) -> Option<Action> {
+        board.actions(team).choose(rng).copied()
+    }
+}
+
+impl Strategy for Greedy {
+    fn name(&self) -> String {
+        "greedy".to_string()
+    }
+
+    fn choose(&self, board: &Board, team: Team, rng: &mut StdRng) -> Option<Action> {
+        best_move(board, team, 1, rng)
+    }
+}
+
+impl Strategy for Minimax {
+    fn name(&self) -> String {
+        format!("minimax:{}", self.depth)
+    }
+
+    fn choose(&self, board: &Board, team: Team, rng: &mut StdRng) -> Option<Action> {
+        best_move(board, team, self.depth, rng)
+    }
+}
+
+// Parses `random`, `greedy` or `minimax:N` with 1 <= N <= `max_depth`.
+pub fn strategy(spec: &str, max_depth: u32) -> Option<Box<dyn Strategy>> {
+    match spec.split_once(':') {
+        None if spec == "random" => Some(Box::new(Random)),
+        None if spec == "greedy" => Some(Box::new(Greedy)),
+        Some(("minimax", depth)) => depth
+            .parse::<u32>()
+            .ok()
+            .filter(|depth| (1..=max_depth).contains(depth))
+            .map(|depth| Box::new(Minimax { depth }) as Box<dyn Strategy>),
+        _ => None,
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Value {
+    Win,
+    Loss,
+    Draw,
+    Invalid,
+    Unknown,
+}
+
+#[derive(Serialize)]
+pub struct Analysis {
+    turn: Team,
+    value: Value,
+    depth: u32,
+    best_moves: Vec<usize>,
+    scores: Vec<Option<i32>>,
+}
+
+// Evaluates the position for the side to move, searching to the end of the
+// game when few enough cells are left. Every depth is capped by `max_depth`,
+// so the value is only reported as a draw when the search still reaches the
+// end of the game on every line.
+pub fn analyze(board: &mut Board, depth: Option<u32>) -> Analysis {
+    let turn = board.side_to_move();
+    let empty_cells = board.empty_cells() as u32;
+    let depth = match (&board.terminal, depth) {
+        (Some(_), _) => 0,
+        (None, Some(depth)) => depth.min(empty_cells).min(max_depth(board)),
+        (None, None) if empty_cells <= MAX_DEPTH => empty_cells.min(max_depth(board)),
+        (None, None) => DEFAULT_DEPTH.min(max_depth(board)),
+    };
+    let mut scores = vec![None; board.width];
+    if board.terminal.is_none() {
+        for (action, score) in Search::new(board).scores(board, turn, depth) {
+            if let Action::Drop(column) = action {
+                scores[column] = Some(score);
+            }
+        }
+    }
+    let best = scores.iter().flatten().max().copied();
+    let value = match (&board.terminal, best) {
+        (Some(Terminal::Draw), _) => Value::Draw,
+        (Some(Terminal::Both), _) => Value::Invalid,
+        (Some(Terminal::Win(winner)), _) if *winner == turn => Value::Win,
+        (Some(Terminal::Win(_)), _) => Value::Loss,
+        (None, Some(best)) if best >= WIN => Value::Win,
+        (None, Some(best)) if best <= -WIN => Value::Loss,
+        (None, Some(_)) if depth >= empty_cells => Value::Draw,
+        _ => Value::Unknown,
+    };
+    let best_moves = scores
+        .iter()
+        .enumerate()
+        .filter(|(_, score)| score.is_some() && **score == best)
+        .map(|(column, _)| column + 1)
+        .collect();
+    Analysis {
+        turn,
+        value,
+        depth,
+        best_moves,
+        scores,
+    }
+}
@@ -0,0 +1,285 @@
+use super::{Action, Team};
+use serde::Serialize;
+use sqlx::types::Uuid;
+use sqlx::{FromRow, PgPool, Postgres, Transaction};
+use std::time::Duration;
+
+const K_FACTOR: f64 = 32.0;
+
+pub struct Snapshot {
+    pub id: Uuid,
+    pub start: String,
+    pub moves: Vec<(Team, Action)>,
+    pub result: Option<&'static str>,
+    pub milk_player: Option<String>,
+    pub cookie_player: Option<String>,
+    pub version: i64,
+    pub round: i64,
+}
+
+#[derive(FromRow)]
+pub struct StoredGame {
+    pub id: Uuid,
+    pub start: String,
+    pub milk_player: Option<String>,
+    pub cookie_player: Option<String>,
+    pub version: i64,
+    pub round: i64,
+}
+
+// The Elo change applied for a round's result, kept so it can be reverted
+// when the result is undone.
+#[derive(FromRow)]
+struct Rating {
+    milk_player: String,
+    cookie_player: String,
+    score: f64,
+    delta: f64,
+}
+
+// A move stored as its kind (`drop`, `pop` or `put`), a row for placements
+// without gravity and a column.
+#[derive(FromRow)]
+pub struct StoredMove {
+    team: String,
+    kind: String,
+    row_index: Option<i32>,
+    column_index: i32,
+}
+
+impl StoredMove {
+    pub fn decode(&self) -> Option<(Team, Action)> {
+        let team = self.team.parse().ok()?;
+        let column = usize::try_from(self.column_index).ok()?;
+        let action = match (self.kind.as_str(), self.row_index) {
+            ("drop", None) => Action::Drop(column),
+            ("pop", None) => Action::Pop(column),
+            ("put", Some(row)) => Action::Put(usize::try_from(row).ok()?, column),
+            _ => return None,
+        };
+        Some((team, action))
+    }
+}
+
+#[derive(FromRow, Serialize)]
+pub struct Standing {
+    name: String,
+    rating: f64,
+    wins: i32,
+    losses: i32,
+    draws: i32,
+}
+
+// Writes the game and its moves unless a newer version is already stored,
+// rating the players once the round reaches a result. A rating for a result
+// that was since undone or changed is reverted first.
+pub async fn save(pool: &PgPool, snapshot: Snapshot) {
+    let mut tx = pool.begin().await.unwrap();
+    let saved = sqlx::query(
+        "INSERT INTO games (id, start, milk_player, cookie_player, result, version, round) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7) \
+         ON CONFLICT (id) DO UPDATE \
+         SET start = EXCLUDED.start, result = EXCLUDED.result, version = EXCLUDED.version, \
+             round = EXCLUDED.round, updated_at = now() \
+         WHERE games.version < EXCLUDED.version;",
+    )
+    .bind(snapshot.id)
+    .bind(&snapshot.start)
+    .bind(&snapshot.milk_player)
+    .bind(&snapshot.cookie_player)
+    .bind(snapshot.result)
+    .bind(snapshot.version)
+    .bind(snapshot.round)
+    .execute(&mut *tx)
+    .await
+    .unwrap()
+    .rows_affected();
+    if saved == 0 {
+        return;
+    }
+
+    sqlx::query("DELETE FROM game_moves WHERE game_id = $1;")
+        .bind(snapshot.id)
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+    let (mut teams, mut kinds, mut rows, mut columns) = (vec![], vec![], vec![], vec![]);
+    for (team, action) in &snapshot.moves {
+        let (kind, row, column) = match *action {
+            Action::Drop(column) => ("drop", None, column),
+            Action::Pop(column) => ("pop", None, column),
+            Action::Put(row, column) => ("put", Some(row as i32), column),
+        };
+        teams.push(team.name());
+        kinds.push(kind);
+        rows.push(row);
+        columns.push(column as i32);
+    }
+    sqlx::query(
+        "INSERT INTO game_moves (game_id, ply, team, kind, row_index, column_index) \
+         SELECT $1, ply::INT, team, kind, row_index, column_index \
+         FROM UNNEST($2::TEXT[], $3::TEXT[], $4::INT[], $5::INT[]) \
+         WITH ORDINALITY AS m(team, kind, row_index, column_index, ply);",
+    )
+    .bind(snapshot.id)
+    .bind(teams)
+    .bind(kinds)
+    .bind(rows)
+    .bind(columns)
+    .execute(&mut *tx)
+    .await
+    .unwrap();
+
+    let score = match (
+        snapshot.result,
+        &snapshot.milk_player,
+        &snapshot.cookie_player,
+    ) {
+        (Some("milk"), Some(milk), Some(cookie)) if milk != cookie => Some(1.0),
+        (Some("cookie"), Some(milk), Some(cookie)) if milk != cookie => Some(0.0),
+        (Some("draw"), Some(milk), Some(cookie)) if milk != cookie => Some(0.5),
+        _ => None,
+    };
+    let rating = sqlx::query_as::<_, Rating>(
+        "SELECT milk_player, cookie_player, score, delta FROM game_ratings \
+         WHERE game_id = $1 AND round = $2;",
+    )
+    .bind(snapshot.id)
+    .bind(snapshot.round)
+    .fetch_optional(&mut *tx)
+    .await
+    .unwrap();
+    if rating.as_ref().map(|rating| rating.score) != score {
+        if let Some(rating) = rating {
+            record(&mut tx, &rating, -1).await;
+            sqlx::query("DELETE FROM game_ratings WHERE game_id = $1 AND round = $2;")
+                .bind(snapshot.id)
+                .bind(snapshot.round)
+                .execute(&mut *tx)
+                .await
+                .unwrap();
+        }
+        if let (Some(score), Some(milk), Some(cookie)) =
+            (score, snapshot.milk_player, snapshot.cookie_player)
+        {
+            let rating = rate(&mut tx, milk, cookie, score).await;
+            sqlx::query(
+                "INSERT INTO game_ratings (game_id, round, milk_player, cookie_player, score, delta) \
+                 VALUES ($1, $2, $3, $4, $5, $6);",
+            )
+            .bind(snapshot.id)
+            .bind(snapshot.round)
+            .bind(&rating.milk_player)
+            .bind(&rating.cookie_player)
+            .bind(rating.score)
+            .bind(rating.delta)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+    }
+
+    tx.commit().await.unwrap();
+}
+
+// Computes the Elo change for a result, `score` being the milk player's
+// result (1 for a win, 0.5 for a draw, 0 for a loss), and records it.
+async fn rate(
+    tx: &mut Transaction<'_, Postgres>,
+    milk: String,
+    cookie: String,
+    score: f64,
+) -> Rating {
+    sqlx::query("INSERT INTO players (name) VALUES ($1), ($2) ON CONFLICT (name) DO NOTHING;")
+        .bind(&milk)
+        .bind(&cookie)
+        .execute(&mut **tx)
+        .await
+        .unwrap();
+    let mut ratings = [0.0; 2];
+    for (rating, name) in ratings.iter_mut().zip([&milk, &cookie]) {
+        *rating =
+            sqlx::query_scalar::<_, f64>("SELECT rating FROM players WHERE name = $1 FOR UPDATE;")
+                .bind(name)
+                .fetch_one(&mut **tx)
+                .await
+                .unwrap();
+    }
+
+    let expected = 1.0 / (1.0 + 10f64.powf((ratings[1] - ratings[0]) / 400.0));
+    let rating = Rating {
+        milk_player: milk,
+        cookie_player: cookie,
+        score,
+        delta: K_FACTOR * (score - expected),
+    };
+    record(tx, &rating, 1).await;
+    rating
+}
+
+// Adds (`sign` 1) or takes back (`sign` -1) a rating in both players' records.
+async fn record(tx: &mut Transaction<'_, Postgres>, rating: &Rating, sign: i32) {
+    let players = [
+        (&rating.milk_player, rating.delta, rating.score),
+        (&rating.cookie_player, -rating.delta, 1.0 - rating.score),
+    ];
+    for (name, delta, score) in players {
+        sqlx::query(
+            "UPDATE players \
+             SET rating = rating + $2, wins = wins + $3, losses = losses + $4, draws = draws + $5 \
+             WHERE name = $1;",
+        )
+        .bind(name)
+        .bind(sign as f64 * delta)
+        .bind(sign * (score == 1.0) as i32)
+        .bind(sign * (score == 0.0) as i32)
+        .bind(sign * (score == 0.5) as i32)
+        .execute(&mut **tx)
+        .await
+        .unwrap();
+    }
+}
+
+// Unfinished games that were active within `since`.
+pub async fn active_games(pool: &PgPool, since: Duration) -> Vec<(StoredGame, Vec<StoredMove>)> {
+    let games = sqlx::query_as::<_, StoredGame>(
+        "SELECT id, start, milk_player, cookie_player, version, round FROM games \
+         WHERE result IS NULL AND updated_at > now() - $1;",
+    )
+    .bind(since)
+    .fetch_all(pool)
+    .await
+    .unwrap();
+
+    let mut restored = Vec::new();
+    for game in games {
+        let moves = sqlx::query_as::<_, StoredMove>(
+            "SELECT team, kind, row_index, column_index FROM game_moves \
+             WHERE game_id = $1 ORDER BY ply;",
+        )
+        .bind(game.id)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+        restored.push((game, moves));
+    }
+    restored
+}
+
+// The stored version and round of a game, whatever its state.
+pub async fn counters(pool: &PgPool, id: Uuid) -> Option<(i64, i64)> {
+    sqlx::query_as::<_, (i64, i64)>("SELECT version, round FROM games WHERE id = $1;")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+        .unwrap()
+}
+
+pub async fn leaderboard(pool: &PgPool) -> Vec<Standing> {
+    sqlx::query_as::<_, Standing>(
+        "SELECT name, rating, wins, losses, draws FROM players ORDER BY rating DESC, name;",
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap()
+}
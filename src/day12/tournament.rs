@@ -0,0 +1,107 @@
+use super::ai::Strategy;
+use super::{Board, Team, Terminal};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct Standing {
+    strategy: String,
+    games: usize,
+    wins: usize,
+    losses: usize,
+    draws: usize,
+    win_rate: f64,
+}
+
+// Plays one game from an empty copy of `template`, returning the winner.
+// PopOut games can repeat positions forever, so overly long games are drawn.
+fn play(
+    template: &Board,
+    milk: &dyn Strategy,
+    cookie: &dyn Strategy,
+    rng: &mut StdRng,
+) -> Option<Team> {
+    let mut board = template.cleared();
+    board.first = Some(Team::Milk);
+    let limit = 4 * board.width * board.height;
+    while board.terminal.is_none() && board.moves.len() < limit {
+        let team = board.side_to_move();
+        let strategy = match team {
+            Team::Milk => milk,
+            Team::Cookie => cookie,
+        };
+        let action = strategy.choose(&board, team, rng)?;
+        board.apply(action, team).ok()?;
+    }
+    match board.terminal {
+        Some(Terminal::Win(winner)) => Some(winner),
+        _ => None,
+    }
+}
+
+// Tournaments run on the blocking pool and can't be cancelled, so their
+// strategies search shallowly and their size is bounded. Both the length of
+// a game and the cost of each search grow with the board's area.
+pub const MAX_DEPTH: u32 = 4;
+const MAX_WORK: usize = 100_000;
+
+pub struct Tournament {
+    pub template: Board,
+    pub strategies: Vec<Box<dyn Strategy>>,
+    pub games: usize,
+    pub seed: u64,
+}
+
+impl Tournament {
+    pub fn within_limits(&self) -> bool {
+        let pairs = self.strategies.len() * (self.strategies.len() - 1) / 2;
+        let area = self.template.width * self.template.height;
+        pairs * self.games * area * area <= MAX_WORK
+    }
+
+    // Every pair of strategies plays `games` games, alternating who moves first.
+    // All randomness comes from `seed`, so a tournament can be replayed exactly.
+    pub fn run(&self) -> Vec<Standing> {
+        let (template, strategies, games) = (&self.template, &self.strategies, self.games);
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut standings = strategies
+            .iter()
+            .map(|strategy| Standing {
+                strategy: strategy.name(),
+                games: 0,
+                wins: 0,
+                losses: 0,
+                draws: 0,
+                win_rate: 0.0,
+            })
+            .collect::<Vec<_>>();
+        for a in 0..strategies.len() {
+            for b in a + 1..strategies.len() {
+                for game in 0..games {
+                    let (milk, cookie) = if game % 2 == 0 { (a, b) } else { (b, a) };
+                    let (winner, loser) =
+                        match play(template, &*strategies[milk], &*strategies[cookie], &mut rng) {
+                            Some(Team::Milk) => (milk, cookie),
+                            Some(Team::Cookie) => (cookie, milk),
+                            None => {
+                                standings[a].draws += 1;
+                                standings[b].draws += 1;
+                                continue;
+                            }
+                        };
+                    standings[winner].wins += 1;
+                    standings[loser].losses += 1;
+                }
+            }
+        }
+        for standing in standings.iter_mut() {
+            standing.games = standing.wins + standing.losses + standing.draws;
+            if standing.games > 0 {
+                standing.win_rate = standing.wins as f64 / standing.games as f64;
+            }
+        }
+        standings.sort_by(|a, b| b.win_rate.total_cmp(&a.win_rate));
+        standings
+    }
+}
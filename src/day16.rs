@@ -1,85 +1,973 @@
-use axum::extract::Path;
-use axum::http::header::{COOKIE, SET_COOKIE};
+use axum::extract::{Path, Query};
+use axum::http::header::SET_COOKIE;
 use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use jsonwebtoken::errors::ErrorKind;
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters, Jwk, JwkSet,
+    OctetKeyPairParameters, PublicKeyUse, RSAKeyParameters,
+};
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use serde::Serialize;
 use serde_json::Value;
+use simple_asn1::ASN1Block;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
 
-const SECRET: &str = "secret";
+// Built in so tokens from the original challenge still decode when the key
+// directory isn't deployed; a key file with the same name replaces it.
+const SANTA_KID: &str = "day16_santa_public_key";
 const SANTA_PUBLIC_KEY_PEM: &str = include_str!("../keys/day16_santa_public_key.pem");
 
-pub async fn unwrap(Path(op): Path<String>, headers: HeaderMap) -> (StatusCode, String) {
-    if op == "unwrap" {
-        let maybe_gift_cookie = headers
-            .get_all(COOKIE)
-            .into_iter()
-            .filter_map(|value| value.to_str().ok())
-            .flat_map(|value| value.trim().split(';'))
-            .filter_map(|cookie| cookie.split_once('='))
-            .find(|(key, _)| *key == "gift")
-            .map(|(_, value)| value);
-
-        let validation: Validation = {
-            let mut validation = Validation::new(Algorithm::HS256);
-            validation.set_required_spec_claims::<String>(&[]);
-            validation
+pub struct Config {
+    // HS256 secret for gifts without a `kid`.
+    pub secret: String,
+    // Directory of `<kid>.pem` public keys, `<kid>.secret` HS256 secrets and
+    // `<kid>.key` private keys for signing gifts.
+    pub key_dir: Option<PathBuf>,
+    // Public key used to decode tokens without a `kid`.
+    pub default_kid: String,
+    // JWKS document to verify against with `source=jwks`, a path or a URL.
+    pub jwks: Option<String>,
+    pub reload_interval: Duration,
+    // Lifetime of wrapped gifts and the `iss`/`aud` they are issued with.
+    pub gift_ttl: Duration,
+    pub issuer: String,
+    pub audience: String,
+    // Clock skew tolerated when checking `exp`.
+    pub leeway: Duration,
+    pub cookie: CookieConfig,
+}
+
+// Attributes of the `gift` cookie; without `max_age` it's a session cookie.
+pub struct CookieConfig {
+    pub http_only: bool,
+    pub secure: bool,
+    pub same_site: SameSite,
+    pub path: String,
+    pub max_age: Option<Duration>,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        CookieConfig {
+            http_only: true,
+            secure: true,
+            same_site: SameSite::Lax,
+            path: "/".to_string(),
+            max_age: Some(Duration::from_secs(3600)),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            secret: "secret".to_string(),
+            key_dir: Some(PathBuf::from("keys")),
+            default_kid: SANTA_KID.to_string(),
+            jwks: None,
+            reload_interval: Duration::from_secs(30),
+            gift_ttl: Duration::from_secs(3600),
+            issuer: "santa".to_string(),
+            audience: "gift".to_string(),
+            leeway: Duration::from_secs(60),
+            cookie: CookieConfig::default(),
+        }
+    }
+}
+
+struct PublicKey {
+    jwk: Jwk,
+    key: DecodingKey,
+    algorithms: Vec<Algorithm>,
+}
+
+impl PublicKey {
+    fn from_pem(kid: &str, pem: &[u8]) -> Option<Self> {
+        let mut jwk = jwk_from_pem(pem)?;
+        jwk.common.key_id = Some(kid.to_string());
+        jwk.common.public_key_use = Some(PublicKeyUse::Signature);
+        PublicKey::from_jwk(jwk)
+    }
+
+    // Only asymmetric keys are accepted, limited to the JWK's `alg` if it has one.
+    fn from_jwk(jwk: Jwk) -> Option<Self> {
+        let algorithms = match &jwk.algorithm {
+            AlgorithmParameters::RSA(_) => vec![
+                Algorithm::RS256,
+                Algorithm::RS384,
+                Algorithm::RS512,
+                Algorithm::PS256,
+                Algorithm::PS384,
+                Algorithm::PS512,
+            ],
+            AlgorithmParameters::EllipticCurve(params) => match params.curve {
+                EllipticCurve::P256 => vec![Algorithm::ES256],
+                EllipticCurve::P384 => vec![Algorithm::ES384],
+                _ => return None,
+            },
+            AlgorithmParameters::OctetKeyPair(params) if params.curve == EllipticCurve::Ed25519 => {
+                vec![Algorithm::EdDSA]
+            }
+            _ => return None,
         };
+        let algorithms = match jwk.common.key_algorithm {
+            Some(alg) => {
+                let alg = Algorithm::from_str(&alg.to_string()).ok()?;
+                vec![alg]
+                    .into_iter()
+                    .filter(|alg| algorithms.contains(alg))
+                    .collect()
+            }
+            None => algorithms,
+        };
+        let key = DecodingKey::from_jwk(&jwk).ok()?;
+        (!algorithms.is_empty()).then_some(PublicKey {
+            jwk,
+            key,
+            algorithms,
+        })
+    }
+}
+
+// How `decode` validates tokens from the key directory or the JWKS document:
+// no claims are required, but `exp` is checked when present.
+fn public_validation(alg: Algorithm) -> Validation {
+    let mut validation = Validation::new(alg);
+    validation.set_required_spec_claims::<String>(&[]);
+    validation
+}
+
+fn verify(token: &str, key: &DecodingKey, validation: &Validation) -> Result<Value, ErrorKind> {
+    decode::<Value>(token, key, validation)
+        .map(|token| token.claims)
+        .map_err(|err| err.into_kind())
+}
+
+// Converts a PEM public key (SubjectPublicKeyInfo, or PKCS#1 for RSA) into
+// its JWK parameters, without `kid` or `alg`.
+fn jwk_from_pem(pem: &[u8]) -> Option<Jwk> {
+    let pem = pem::parse(pem).ok()?;
+    let blocks = simple_asn1::from_der(pem.contents()).ok()?;
+    let b64 = |bytes: &[u8]| URL_SAFE_NO_PAD.encode(bytes);
+    let rsa = |blocks: &[ASN1Block]| match blocks {
+        [ASN1Block::Sequence(_, fields)] => match fields.as_slice() {
+            [ASN1Block::Integer(_, n), ASN1Block::Integer(_, e)] => {
+                Some(AlgorithmParameters::RSA(RSAKeyParameters {
+                    n: b64(&n.to_bytes_be().1),
+                    e: b64(&e.to_bytes_be().1),
+                    ..Default::default()
+                }))
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    let algorithm = if pem.tag() == "RSA PUBLIC KEY" {
+        rsa(&blocks)?
+    } else {
+        let [ASN1Block::Sequence(_, fields)] = blocks.as_slice() else {
+            return None;
+        };
+        let [ASN1Block::Sequence(_, identifier), ASN1Block::BitString(_, _, key)] =
+            fields.as_slice()
+        else {
+            return None;
+        };
+        let oids = identifier
+            .iter()
+            .filter_map(|block| match block {
+                ASN1Block::ObjectIdentifier(_, oid) => oid.as_vec::<u64>().ok(),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let curve = |curve: EllipticCurve, size: usize| match key.as_slice() {
+            [0x04, point @ ..] if point.len() == 2 * size => Some(
+                AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                    curve,
+                    x: b64(&point[..size]),
+                    y: b64(&point[size..]),
+                    ..Default::default()
+                }),
+            ),
+            _ => None,
+        };
+        match oids
+            .iter()
+            .map(Vec::as_slice)
+            .collect::<Vec<_>>()
+            .as_slice()
+        {
+            [[1, 2, 840, 113549, 1, 1, 1]] => rsa(&simple_asn1::from_der(key).ok()?)?,
+            [[1, 2, 840, 10045, 2, 1], [1, 2, 840, 10045, 3, 1, 7]] => {
+                curve(EllipticCurve::P256, 32)?
+            }
+            [[1, 2, 840, 10045, 2, 1], [1, 3, 132, 0, 34]] => curve(EllipticCurve::P384, 48)?,
+            [[1, 3, 101, 112]] if key.len() == 32 => {
+                AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    curve: EllipticCurve::Ed25519,
+                    x: b64(key),
+                    ..Default::default()
+                })
+            }
+            _ => return None,
+        }
+    };
+    Some(Jwk {
+        common: CommonParameters::default(),
+        algorithm,
+    })
+}
+
+struct PrivateKey {
+    key: EncodingKey,
+    algorithms: Vec<Algorithm>,
+}
+
+impl PrivateKey {
+    fn from_pem(pem: &[u8]) -> Option<Self> {
+        if let Ok(key) = EncodingKey::from_rsa_pem(pem) {
+            let algorithms = vec![
+                Algorithm::RS256,
+                Algorithm::RS384,
+                Algorithm::RS512,
+                Algorithm::PS256,
+                Algorithm::PS384,
+                Algorithm::PS512,
+            ];
+            Some(PrivateKey { key, algorithms })
+        } else if let Ok(key) = EncodingKey::from_ec_pem(pem) {
+            let algorithms = vec![Algorithm::ES256, Algorithm::ES384];
+            Some(PrivateKey { key, algorithms })
+        } else if let Ok(key) = EncodingKey::from_ed_pem(pem) {
+            let algorithms = vec![Algorithm::EdDSA];
+            Some(PrivateKey { key, algorithms })
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Default)]
+struct Keyring {
+    secrets: HashMap<String, Vec<u8>>,
+    public_keys: HashMap<String, PublicKey>,
+    private_keys: HashMap<String, PrivateKey>,
+    // File names, modification times and sizes the keys were loaded from.
+    fingerprint: Vec<(PathBuf, SystemTime, u64)>,
+}
+
+impl Keyring {
+    fn builtin() -> Self {
+        let mut keyring = Keyring::default();
+        if let Some(key) = PublicKey::from_pem(SANTA_KID, SANTA_PUBLIC_KEY_PEM.as_bytes()) {
+            keyring.public_keys.insert(SANTA_KID.to_string(), key);
+        }
+        keyring
+    }
 
-        let maybe_token = maybe_gift_cookie.and_then(|cookie| {
-            decode::<Value>(
-                cookie,
-                &DecodingKey::from_secret(SECRET.as_ref()),
-                &validation,
-            )
-            .ok()
+    // Unreadable or unparseable files are skipped so one bad key doesn't take
+    // the others down with it.
+    fn load(dir: &std::path::Path, fingerprint: Vec<(PathBuf, SystemTime, u64)>) -> Self {
+        let mut keyring = Keyring {
+            fingerprint,
+            ..Keyring::builtin()
+        };
+        for (path, _, _) in &keyring.fingerprint {
+            let (Some(kid), Ok(contents)) = (
+                path.file_stem().and_then(|stem| stem.to_str()),
+                fs::read(dir.join(path)),
+            ) else {
+                continue;
+            };
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("pem") => {
+                    if let Some(key) = PublicKey::from_pem(kid, &contents) {
+                        keyring.public_keys.insert(kid.to_string(), key);
+                    }
+                }
+                Some("secret") => {
+                    let secret = contents.trim_ascii().to_vec();
+                    keyring.secrets.insert(kid.to_string(), secret);
+                }
+                Some("key") => {
+                    if let Some(key) = PrivateKey::from_pem(&contents) {
+                        keyring.private_keys.insert(kid.to_string(), key);
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Gifts are verified with the public half, so a private key is only
+        // used alongside its `<kid>.pem` and for the algorithms it allows.
+        let public_keys = &keyring.public_keys;
+        keyring.private_keys.retain(|kid, private_key| {
+            let Some(public_key) = public_keys.get(kid) else {
+                return false;
+            };
+            private_key
+                .algorithms
+                .retain(|alg| public_key.algorithms.contains(alg));
+            !private_key.algorithms.is_empty()
         });
+        keyring
+    }
+}
+
+fn fingerprint(dir: &std::path::Path) -> Vec<(PathBuf, SystemTime, u64)> {
+    let mut fingerprint = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            let modified = metadata.modified().ok()?;
+            Some((PathBuf::from(entry.file_name()), modified, metadata.len()))
+        })
+        .collect::<Vec<_>>();
+    fingerprint.sort();
+    fingerprint
+}
+
+#[derive(Debug, PartialEq)]
+enum GiftError {
+    Missing,
+    Malformed,
+    Forged,
+    Expired,
+    NotYetValid,
+    WrongIssuer,
+    WrongAudience,
+}
+
+impl GiftError {
+    fn status(&self) -> StatusCode {
+        match self {
+            GiftError::Missing | GiftError::Malformed => StatusCode::BAD_REQUEST,
+            GiftError::Forged | GiftError::Expired | GiftError::NotYetValid => {
+                StatusCode::UNAUTHORIZED
+            }
+            GiftError::WrongIssuer | GiftError::WrongAudience => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            GiftError::Missing => "missing gift cookie",
+            GiftError::Malformed => "malformed gift",
+            GiftError::Forged => "forged gift",
+            GiftError::Expired => "expired gift",
+            GiftError::NotYetValid => "gift not yet valid",
+            GiftError::WrongIssuer => "gift from an unknown issuer",
+            GiftError::WrongAudience => "gift for another audience",
+        }
+    }
+}
+
+// Why no key could verify a gift.
+enum KeyError {
+    Unknown,
+    Algorithm,
+}
+
+impl From<KeyError> for GiftError {
+    fn from(_: KeyError) -> Self {
+        GiftError::Forged
+    }
+}
+
+impl From<KeyError> for ErrorKind {
+    fn from(error: KeyError) -> Self {
+        match error {
+            KeyError::Unknown => ErrorKind::InvalidSignature,
+            KeyError::Algorithm => ErrorKind::InvalidAlgorithm,
+        }
+    }
+}
+
+impl From<ErrorKind> for GiftError {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::InvalidSignature | ErrorKind::InvalidAlgorithm => GiftError::Forged,
+            ErrorKind::ExpiredSignature => GiftError::Expired,
+            ErrorKind::ImmatureSignature => GiftError::NotYetValid,
+            ErrorKind::InvalidIssuer => GiftError::WrongIssuer,
+            ErrorKind::InvalidAudience => GiftError::WrongAudience,
+            _ => GiftError::Malformed,
+        }
+    }
+}
+
+const GIFT_COOKIE: &str = "gift";
+
+const JWKS_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Claims added by `wrap` and removed again by `unwrap`.
+const GIFT_CLAIMS: [&str; 4] = ["iat", "exp", "iss", "aud"];
+
+// Where an inspected token would be verified: `/16/unwrap` for `gift`, or
+// `/16/decode` with the same `source`.
+#[derive(Clone, Copy)]
+enum Source {
+    Gift,
+    Local,
+    Jwks,
+}
+
+// An unverified look at a token followed by each check its source makes. A
+// check is `null` when it wasn't run, such as `signature` without a key or
+// `not_before` without an `nbf` claim; missing required claims fail theirs.
+#[derive(Serialize)]
+struct Inspection {
+    header: Header,
+    algorithm: Algorithm,
+    kid: Option<String>,
+    claims: Value,
+    timestamps: serde_json::Map<String, Value>,
+    missing: Vec<String>,
+    checks: Checks,
+    valid: bool,
+    failed: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+struct Checks {
+    key: bool,
+    algorithm: Option<bool>,
+    signature: Option<bool>,
+    expiry: Option<bool>,
+    not_before: Option<bool>,
+    issuer: Option<bool>,
+    audience: Option<bool>,
+}
+
+impl Checks {
+    fn failed(&self) -> Vec<&'static str> {
+        [
+            ("key", Some(self.key)),
+            ("algorithm", self.algorithm),
+            ("signature", self.signature),
+            ("expiry", self.expiry),
+            ("not_before", self.not_before),
+            ("issuer", self.issuer),
+            ("audience", self.audience),
+        ]
+        .into_iter()
+        .filter(|(_, passed)| *passed == Some(false))
+        .map(|(check, _)| check)
+        .collect()
+    }
+}
 
-        if let Some(token) = maybe_token {
-            (StatusCode::OK, token.claims.to_string())
+// Renders a numeric date claim as an ISO-8601 timestamp.
+fn iso8601(timestamp: &Value) -> Option<String> {
+    let timestamp = time::OffsetDateTime::from_unix_timestamp(timestamp.as_i64()?).ok()?;
+    timestamp
+        .format(&time::format_description::well_known::Rfc3339)
+        .ok()
+}
+
+pub struct State {
+    secret: Vec<u8>,
+    key_dir: Option<PathBuf>,
+    default_kid: String,
+    reload_interval: Duration,
+    keys: RwLock<Keyring>,
+    jwks_source: Option<String>,
+    jwks: tokio::sync::RwLock<Vec<PublicKey>>,
+    gift_ttl: Duration,
+    issuer: String,
+    audience: String,
+    leeway: Duration,
+    cookie: CookieConfig,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State::new(Config::default())
+    }
+}
+
+impl State {
+    pub fn new(config: Config) -> Self {
+        let state = State {
+            secret: config.secret.into_bytes(),
+            key_dir: config.key_dir,
+            default_kid: config.default_kid,
+            reload_interval: config.reload_interval,
+            keys: RwLock::new(Keyring::builtin()),
+            jwks_source: config.jwks,
+            jwks: tokio::sync::RwLock::new(Vec::new()),
+            gift_ttl: config.gift_ttl,
+            issuer: config.issuer,
+            audience: config.audience,
+            leeway: config.leeway,
+            cookie: config.cookie,
+        };
+        state.reload();
+        state
+    }
+
+    // Polls the key directory, reloading every key when any file changes, and
+    // refreshes the JWKS document in a task of its own, so a slow JWKS server
+    // can't hold up key reloads.
+    pub fn watch(self: &Arc<Self>) {
+        if self.key_dir.is_some() {
+            let state = Arc::clone(self);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(state.reload_interval);
+                loop {
+                    interval.tick().await;
+                    state.reload();
+                }
+            });
+        }
+        if self.jwks_source.is_some() {
+            let state = Arc::clone(self);
+            tokio::spawn(async move {
+                let client = reqwest::Client::builder()
+                    .timeout(JWKS_TIMEOUT)
+                    .build()
+                    .unwrap();
+                let mut interval = tokio::time::interval(state.reload_interval);
+                loop {
+                    interval.tick().await;
+                    state.refresh_jwks(&client).await;
+                }
+            });
+        }
+    }
+
+    // Keeps the previous keys when the document can't be fetched or parsed.
+    async fn refresh_jwks(&self, client: &reqwest::Client) {
+        let Some(source) = &self.jwks_source else {
+            return;
+        };
+        let document = if source.starts_with("http://") || source.starts_with("https://") {
+            match client.get(source).send().await {
+                Ok(response) => response.text().await.ok(),
+                Err(_) => None,
+            }
         } else {
-            (StatusCode::BAD_REQUEST, String::new())
+            tokio::fs::read_to_string(source).await.ok()
+        };
+        if let Some(set) =
+            document.and_then(|document| serde_json::from_str::<JwkSet>(&document).ok())
+        {
+            let keys = set
+                .keys
+                .into_iter()
+                .filter_map(PublicKey::from_jwk)
+                .collect();
+            *self.jwks.write().await = keys;
+        }
+    }
+
+    fn reload(&self) {
+        let Some(dir) = &self.key_dir else {
+            return;
+        };
+        let fingerprint = fingerprint(dir);
+        if self.keys.read().unwrap().fingerprint != fingerprint {
+            let keyring = Keyring::load(dir, fingerprint);
+            *self.keys.write().unwrap() = keyring;
+        }
+    }
+
+    // The HS256 secret for `kid`, or the configured secret without one.
+    fn secret(&self, kid: Option<&str>) -> Option<Vec<u8>> {
+        match kid {
+            Some(kid) => self.keys.read().unwrap().secrets.get(kid).cloned(),
+            None => Some(self.secret.clone()),
+        }
+    }
+
+    // The private key for `kid` if it signs with `alg`, or the first one by
+    // `kid` that does.
+    fn private_key(&self, kid: Option<&str>, alg: Algorithm) -> Option<(String, EncodingKey)> {
+        let keys = self.keys.read().unwrap();
+        let (kid, key) = match kid {
+            Some(kid) => keys.private_keys.get_key_value(kid)?,
+            None => keys
+                .private_keys
+                .iter()
+                .filter(|(_, key)| key.algorithms.contains(&alg))
+                .min_by_key(|(kid, _)| *kid)?,
+        };
+        key.algorithms
+            .contains(&alg)
+            .then(|| (kid.clone(), key.key.clone()))
+    }
+
+    // Adds the issue time, expiry, issuer and audience to a gift's claims.
+    fn wrap_gift(&self, gift: &mut serde_json::Map<String, Value>) {
+        let now = jsonwebtoken::get_current_timestamp();
+        gift.insert("iat".to_string(), now.into());
+        gift.insert("exp".to_string(), (now + self.gift_ttl.as_secs()).into());
+        gift.insert("iss".to_string(), self.issuer.clone().into());
+        gift.insert("aud".to_string(), self.audience.clone().into());
+    }
+
+    fn gift_cookie(&self, token: String) -> Cookie<'static> {
+        let mut cookie = Cookie::build((GIFT_COOKIE, token))
+            .http_only(self.cookie.http_only)
+            .secure(self.cookie.secure)
+            .same_site(self.cookie.same_site)
+            .path(self.cookie.path.clone())
+            .build();
+        if let Some(max_age) = self.cookie.max_age {
+            cookie.set_max_age(time::Duration::seconds(max_age.as_secs() as i64));
+        }
+        cookie
+    }
+
+    // The key a gift is verified with: a secret for HS256, otherwise the
+    // public key named by its `kid`.
+    fn gift_key(&self, header: &Header) -> Result<DecodingKey, KeyError> {
+        match header.alg {
+            Algorithm::HS256 => self
+                .secret(header.kid.as_deref())
+                .map(|secret| DecodingKey::from_secret(&secret))
+                .ok_or(KeyError::Unknown),
+            Algorithm::HS384 | Algorithm::HS512 => Err(KeyError::Algorithm),
+            alg => {
+                let keys = self.keys.read().unwrap();
+                let public_key = header
+                    .kid
+                    .as_ref()
+                    .and_then(|kid| keys.public_keys.get(kid))
+                    .ok_or(KeyError::Unknown)?;
+                if public_key.algorithms.contains(&alg) {
+                    Ok(public_key.key.clone())
+                } else {
+                    Err(KeyError::Algorithm)
+                }
+            }
+        }
+    }
+
+    fn gift_validation(&self, alg: Algorithm) -> Validation {
+        let mut validation = Validation::new(alg);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.validate_nbf = true;
+        validation.leeway = self.leeway.as_secs();
+        validation
+    }
+
+    // Verifies the gift cookie and returns its claims without the ones added
+    // by `wrap_gift`.
+    fn unwrap_gift(&self, cookie: Option<&str>) -> Result<Value, GiftError> {
+        let cookie = cookie.ok_or(GiftError::Missing)?;
+        let header = decode_header(cookie).map_err(|err| err.into_kind())?;
+        let key = self.gift_key(&header)?;
+        let mut claims = verify(cookie, &key, &self.gift_validation(header.alg))?;
+        if let Some(claims) = claims.as_object_mut() {
+            for claim in GIFT_CLAIMS {
+                claims.remove(claim);
+            }
+        }
+        Ok(claims)
+    }
+
+    // Decodes `token` without verifying it, then looks up its key and checks
+    // the signature and each claim the same way `source` does, so the report
+    // says exactly which ones failed. `valid` is the verdict `source` reaches.
+    async fn inspect(&self, token: &str, source: Source) -> Option<Inspection> {
+        let header = decode_header(token).ok()?;
+        let payload = token.split('.').nth(1)?;
+        let claims =
+            serde_json::from_slice::<Value>(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+
+        let timestamps = ["iat", "nbf", "exp"]
+            .into_iter()
+            .filter_map(|claim| Some((claim.to_string(), iso8601(claims.get(claim)?)?.into())))
+            .collect();
+
+        let (keys, validation, valid) = match source {
+            Source::Gift => (
+                self.gift_key(&header).map(|key| vec![key]),
+                self.gift_validation(header.alg),
+                self.unwrap_gift(Some(token)).is_ok(),
+            ),
+            Source::Local => (
+                self.public_key(&header).map(|key| vec![key]),
+                public_validation(header.alg),
+                self.decode_public(token).is_ok(),
+            ),
+            Source::Jwks => (
+                self.jwks_keys(&header).await,
+                public_validation(header.alg),
+                self.decode_jwks(token).await.is_ok(),
+            ),
+        };
+        let signature = keys.as_ref().ok().map(|keys| {
+            let mut signature_only = Validation::new(header.alg);
+            signature_only.set_required_spec_claims::<String>(&[]);
+            signature_only.validate_exp = false;
+            signature_only.validate_aud = false;
+            keys.iter()
+                .any(|key| decode::<Value>(token, key, &signature_only).is_ok())
+        });
+
+        let mut missing = validation
+            .required_spec_claims
+            .iter()
+            .filter(|claim| claims.get(claim.as_str()).is_none())
+            .cloned()
+            .collect::<Vec<_>>();
+        missing.sort();
+        let check =
+            |name: &str, enabled: bool, passes: &dyn Fn(&Value) -> bool| match claims.get(name) {
+                _ if !enabled => None,
+                Some(value) => Some(passes(value)),
+                None if validation.required_spec_claims.contains(name) => Some(false),
+                None => None,
+            };
+        let now = jsonwebtoken::get_current_timestamp();
+        let leeway = validation.leeway;
+        // As in `jsonwebtoken`, a list of issuers or audiences passes if any is allowed.
+        let accepts = |allowed: &Option<HashSet<String>>, value: &Value| {
+            let accepted = |value: &Value| {
+                allowed.as_ref().is_some_and(|allowed| {
+                    value.as_str().is_some_and(|value| allowed.contains(value))
+                })
+            };
+            match value {
+                Value::Array(values) => values.iter().any(accepted),
+                value => accepted(value),
+            }
+        };
+        let checks = Checks {
+            key: !matches!(keys, Err(KeyError::Unknown)),
+            algorithm: match keys {
+                Ok(_) => Some(true),
+                Err(KeyError::Algorithm) => Some(false),
+                Err(KeyError::Unknown) => None,
+            },
+            signature,
+            expiry: check("exp", validation.validate_exp, &|exp| {
+                exp.as_u64().is_some_and(|exp| exp + leeway >= now)
+            }),
+            not_before: check("nbf", validation.validate_nbf, &|nbf| {
+                nbf.as_u64().is_some_and(|nbf| nbf <= now + leeway)
+            }),
+            issuer: check("iss", validation.iss.is_some(), &|iss| {
+                accepts(&validation.iss, iss)
+            }),
+            audience: check("aud", validation.validate_aud, &|aud| {
+                accepts(&validation.aud, aud)
+            }),
+        };
+        Some(Inspection {
+            algorithm: header.alg,
+            kid: header.kid.clone(),
+            header,
+            claims,
+            timestamps,
+            missing,
+            failed: checks.failed(),
+            checks,
+            valid,
+        })
+    }
+
+    // The public key named by a token's `kid`, falling back to the default key.
+    fn public_key(&self, header: &Header) -> Result<DecodingKey, KeyError> {
+        let kid = header.kid.as_deref().unwrap_or(&self.default_kid);
+        let keys = self.keys.read().unwrap();
+        let public_key = keys.public_keys.get(kid).ok_or(KeyError::Unknown)?;
+        if public_key.algorithms.contains(&header.alg) {
+            Ok(public_key.key.clone())
+        } else {
+            Err(KeyError::Algorithm)
+        }
+    }
+
+    // The JWKS keys matching a token's `kid` (every key without one) that
+    // allow its algorithm.
+    async fn jwks_keys(&self, header: &Header) -> Result<Vec<DecodingKey>, KeyError> {
+        let keys = self.jwks.read().await;
+        let named = keys
+            .iter()
+            .filter(|key| {
+                header
+                    .kid
+                    .as_ref()
+                    .is_none_or(|kid| key.jwk.common.key_id.as_ref() == Some(kid))
+            })
+            .collect::<Vec<_>>();
+        if named.is_empty() {
+            return Err(KeyError::Unknown);
+        }
+        let allowed = named
+            .into_iter()
+            .filter(|key| key.algorithms.contains(&header.alg))
+            .map(|key| key.key.clone())
+            .collect::<Vec<_>>();
+        if allowed.is_empty() {
+            Err(KeyError::Algorithm)
+        } else {
+            Ok(allowed)
+        }
+    }
+
+    fn decode_public(&self, token: &str) -> Result<Value, ErrorKind> {
+        let header = decode_header(token).map_err(|err| err.into_kind())?;
+        let key = self.public_key(&header)?;
+        verify(token, &key, &public_validation(header.alg))
+    }
+
+    // Tries every matching JWKS key; without one the token counts as forged.
+    async fn decode_jwks(&self, token: &str) -> Result<Value, ErrorKind> {
+        let header = decode_header(token).map_err(|err| err.into_kind())?;
+        let keys = self
+            .jwks_keys(&header)
+            .await
+            .map_err(|_| ErrorKind::InvalidSignature)?;
+        let validation = public_validation(header.alg);
+        let mut result = Err(ErrorKind::InvalidSignature);
+        for key in &keys {
+            result = verify(token, key, &validation);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+}
+
+// Publishes the public halves of the keys gifts can be signed with; keys
+// that only verify, like the built-in Santa key, stay unpublished.
+pub async fn jwks(state: Arc<State>) -> Json<JwkSet> {
+    let keys = state.keys.read().unwrap();
+    let mut jwks = keys
+        .private_keys
+        .keys()
+        .filter_map(|kid| keys.public_keys.get(kid))
+        .map(|key| key.jwk.clone())
+        .collect::<Vec<_>>();
+    jwks.sort_by(|a, b| a.common.key_id.cmp(&b.common.key_id));
+    Json(JwkSet { keys: jwks })
+}
+
+pub async fn unwrap(
+    state: Arc<State>,
+    Path(op): Path<String>,
+    jar: CookieJar,
+) -> (StatusCode, String) {
+    if op == "unwrap" {
+        match state.unwrap_gift(jar.get(GIFT_COOKIE).map(Cookie::value)) {
+            Ok(gift) => (StatusCode::OK, gift.to_string()),
+            Err(error) => (error.status(), error.message().to_string()),
         }
     } else {
         (StatusCode::NOT_FOUND, String::new())
     }
 }
 
-pub async fn jwt(Path(op): Path<String>, body: String) -> (StatusCode, HeaderMap, String) {
+pub async fn jwt(
+    state: Arc<State>,
+    Path(op): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    body: String,
+) -> (StatusCode, HeaderMap, String) {
     match op.as_str() {
         "wrap" => {
-            let payload = serde_json::from_str::<Value>(&body).unwrap();
-            let token = encode(
-                &Header::default(),
-                &payload,
-                &EncodingKey::from_secret(SECRET.as_ref()),
-            )
-            .unwrap();
+            let mut payload = serde_json::from_str::<Value>(&body).ok();
+            // Claims `unwrap` would strip can't be part of the gift itself.
+            let Some(gift) = payload
+                .as_mut()
+                .and_then(Value::as_object_mut)
+                .filter(|gift| !GIFT_CLAIMS.iter().any(|claim| gift.contains_key(*claim)))
+            else {
+                return (StatusCode::BAD_REQUEST, HeaderMap::new(), String::new());
+            };
+            state.wrap_gift(gift);
+            // `kid` signs with one of the key directory's secrets, or with a
+            // private key when `alg` is asymmetric.
+            let kid = params.get("kid").cloned();
+            let alg = match params.get("alg").map(|alg| Algorithm::from_str(alg)) {
+                None => Algorithm::HS256,
+                Some(Ok(Algorithm::HS384 | Algorithm::HS512)) | Some(Err(_)) => {
+                    return (StatusCode::BAD_REQUEST, HeaderMap::new(), String::new())
+                }
+                Some(Ok(alg)) => alg,
+            };
+            let key = if alg == Algorithm::HS256 {
+                state
+                    .secret(kid.as_deref())
+                    .map(|secret| (kid, EncodingKey::from_secret(&secret)))
+            } else {
+                state
+                    .private_key(kid.as_deref(), alg)
+                    .map(|(kid, key)| (Some(kid), key))
+            };
+            let Some((kid, key)) = key else {
+                return (StatusCode::BAD_REQUEST, HeaderMap::new(), String::new());
+            };
+            let header = Header {
+                alg,
+                kid,
+                ..Default::default()
+            };
+            let Ok(token) = encode(&header, &payload, &key) else {
+                return (StatusCode::BAD_REQUEST, HeaderMap::new(), String::new());
+            };
+            let cookie = state.gift_cookie(token);
+            let mut headers = HeaderMap::new();
+            headers.append(SET_COOKIE, cookie.to_string().parse().unwrap());
+            (StatusCode::OK, headers, String::new())
+        }
+        "logout" => {
+            let mut cookie = state.gift_cookie(String::new());
+            cookie.make_removal();
             let mut headers = HeaderMap::new();
-            headers.append(SET_COOKIE, format!("gift={}", token).parse().unwrap());
+            headers.append(SET_COOKIE, cookie.to_string().parse().unwrap());
             (StatusCode::OK, headers, String::new())
         }
-        "decode" => {
-            let validation = {
-                let mut validation = Validation::new(Algorithm::RS256);
-                validation.set_required_spec_claims::<String>(&[]);
-                validation.algorithms = vec![Algorithm::RS256, Algorithm::RS512];
-                validation
+        "inspect" => {
+            let source = match params.get("source").map(String::as_str) {
+                Some("gift") | None => Source::Gift,
+                Some("local") => Source::Local,
+                Some("jwks") => Source::Jwks,
+                _ => return (StatusCode::BAD_REQUEST, HeaderMap::new(), String::new()),
             };
-            match decode::<Value>(
-                &body,
-                &DecodingKey::from_rsa_pem(SANTA_PUBLIC_KEY_PEM.as_ref()).unwrap(),
-                &validation,
-            ) {
-                Ok(token) => (StatusCode::OK, HeaderMap::new(), token.claims.to_string()),
-                Err(err) => {
-                    if let ErrorKind::InvalidSignature = err.kind() {
-                        (StatusCode::UNAUTHORIZED, HeaderMap::new(), String::new())
-                    } else {
-                        (StatusCode::BAD_REQUEST, HeaderMap::new(), String::new())
-                    }
-                }
+            match state.inspect(body.trim(), source).await {
+                Some(inspection) => (
+                    StatusCode::OK,
+                    HeaderMap::new(),
+                    serde_json::to_string(&inspection).unwrap(),
+                ),
+                None => (StatusCode::BAD_REQUEST, HeaderMap::new(), String::new()),
             }
         }
+        "decode" => match params.get("source").map(String::as_str) {
+            Some("local") | None => respond_decoded(state.decode_public(&body)),
+            Some("jwks") => respond_decoded(state.decode_jwks(&body).await),
+            _ => (StatusCode::BAD_REQUEST, HeaderMap::new(), String::new()),
+        },
         _ => (StatusCode::NOT_FOUND, HeaderMap::new(), String::new()),
     }
 }
+
+fn respond_decoded(decoded: Result<Value, ErrorKind>) -> (StatusCode, HeaderMap, String) {
+    match decoded {
+        Ok(claims) => (StatusCode::OK, HeaderMap::new(), claims.to_string()),
+        Err(ErrorKind::InvalidSignature) => {
+            (StatusCode::UNAUTHORIZED, HeaderMap::new(), String::new())
+        }
+        Err(_) => (StatusCode::BAD_REQUEST, HeaderMap::new(), String::new()),
+    }
+}
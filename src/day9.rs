@@ -1,84 +1,596 @@
-use axum::extract::Path;
-use axum::http::header::CONTENT_TYPE;
+use axum::extract::{Path, Query};
+use axum::http::header::{ACCEPT, CONTENT_TYPE};
 use axum::http::{HeaderMap, StatusCode};
 use leaky_bucket::RateLimiter;
-use serde::{Deserialize, Serialize};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::{Decimal, RoundingStrategy};
+use rust_decimal_macros::dec;
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use serde_json::value::RawValue;
+use serde_json::{Map, Number, Value};
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::{FromRow, PgPool};
+use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+pub const BUCKET_SIZE: usize = 5;
+const REFILL_INTERVAL: Duration = Duration::from_millis(1000);
+const BUCKET_ID: &str = "milk";
+
+pub enum Backend {
+    Memory,
+    Postgres(PgPool),
+}
+
+pub struct Config {
+    pub batch_tokens: usize,
+    // Where the token bucket and the stock ledger are kept.
+    pub limiter: Backend,
+    pub inventory: Backend,
+    pub rounding: Rounding,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            batch_tokens: 1,
+            limiter: Backend::Memory,
+            inventory: Backend::Memory,
+            rounding: Rounding::HalfEven,
+        }
+    }
+}
+
+enum Limiter {
+    Memory(Mutex<RateLimiter>),
+    Postgres(PgPool),
+}
+
+impl Limiter {
+    fn new_rate_limiter() -> RateLimiter {
+        RateLimiter::builder()
+            .initial(BUCKET_SIZE)
+            .max(BUCKET_SIZE)
+            .interval(REFILL_INTERVAL)
+            .build()
+    }
+
+    async fn try_acquire(&self, tokens: usize) -> bool {
+        match self {
+            Limiter::Memory(limiter) => limiter.lock().unwrap().try_acquire(tokens),
+            Limiter::Postgres(pool) => sqlx::query(
+                "UPDATE milk_bucket \
+                 SET tokens = LEAST($2, tokens + EXTRACT(EPOCH FROM now() - updated_at) / $3) - $4, updated_at = now() \
+                 WHERE id = $1 AND LEAST($2, tokens + EXTRACT(EPOCH FROM now() - updated_at) / $3) >= $4 \
+                 RETURNING tokens;",
+            )
+            .bind(BUCKET_ID)
+            .bind(BUCKET_SIZE as f64)
+            .bind(REFILL_INTERVAL.as_secs_f64())
+            .bind(tokens as f64)
+            .fetch_optional(pool)
+            .await
+            .unwrap()
+            .is_some(),
+        }
+    }
+
+    async fn reset(&self) {
+        match self {
+            Limiter::Memory(limiter) => {
+                let mut rl = limiter.lock().unwrap();
+                *rl = Limiter::new_rate_limiter();
+            }
+            Limiter::Postgres(pool) => {
+                sqlx::query(
+                    "INSERT INTO milk_bucket (id, tokens) VALUES ($1, $2) \
+                     ON CONFLICT (id) DO UPDATE SET tokens = $2, updated_at = now();",
+                )
+                .bind(BUCKET_ID)
+                .bind(BUCKET_SIZE as f64)
+                .execute(pool)
+                .await
+                .unwrap();
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Movement {
+    Withdrawal,
+    Refill,
+}
+
+impl Movement {
+    fn kind(self) -> &'static str {
+        match self {
+            Movement::Withdrawal => "withdrawal",
+            Movement::Refill => "refill",
+        }
+    }
+
+    fn delta(self, liters: Decimal) -> Decimal {
+        match self {
+            Movement::Withdrawal => -liters,
+            Movement::Refill => liters,
+        }
+    }
+}
+
+#[derive(FromRow, Clone, Serialize)]
+struct LedgerEntry {
+    kind: String,
+    liters: Decimal,
+    stock: Decimal,
+    created_at: DateTime<Utc>,
+}
+
+enum Inventory {
+    Memory(Mutex<Vec<LedgerEntry>>),
+    Postgres(PgPool),
+}
+
+impl Inventory {
+    // Returns `None` when a withdrawal exceeds the current stock.
+    async fn record(&self, movement: Movement, liters: Decimal) -> Option<LedgerEntry> {
+        match self {
+            Inventory::Memory(ledger) => {
+                let mut ledger = ledger.lock().unwrap();
+                let stock = ledger.last().map_or(Decimal::ZERO, |entry| entry.stock)
+                    + movement.delta(liters);
+                if stock < Decimal::ZERO {
+                    return None;
+                }
+                let entry = LedgerEntry {
+                    kind: movement.kind().to_string(),
+                    liters,
+                    stock,
+                    created_at: Utc::now(),
+                };
+                ledger.push(entry.clone());
+                Some(entry)
+            }
+            Inventory::Postgres(pool) => sqlx::query_as::<_, LedgerEntry>(
+                "WITH stock AS ( \
+                     UPDATE milk_stock SET liters = liters + $2 \
+                     WHERE id = $1 AND liters + $2 >= 0 RETURNING liters \
+                 ) \
+                 INSERT INTO milk_ledger (kind, liters, stock) SELECT $3, $4, liters FROM stock \
+                 RETURNING kind, liters, stock, created_at;",
+            )
+            .bind(BUCKET_ID)
+            .bind(movement.delta(liters))
+            .bind(movement.kind())
+            .bind(liters)
+            .fetch_optional(pool)
+            .await
+            .unwrap(),
+        }
+    }
+
+    async fn history(&self) -> Vec<LedgerEntry> {
+        match self {
+            Inventory::Memory(ledger) => ledger.lock().unwrap().clone(),
+            Inventory::Postgres(pool) => sqlx::query_as::<_, LedgerEntry>(
+                "SELECT kind, liters, stock, created_at FROM milk_ledger ORDER BY id;",
+            )
+            .fetch_all(pool)
+            .await
+            .unwrap(),
+        }
+    }
+}
+
 pub struct State {
-    limiter: Mutex<RateLimiter>,
+    limiter: Limiter,
+    inventory: Inventory,
+    batch_tokens: usize,
+    rounding: Rounding,
 }
 
 impl Default for State {
     fn default() -> Self {
+        State::new(Config::default())
+    }
+}
+
+impl State {
+    pub fn new(config: Config) -> Self {
         State {
-            limiter: Mutex::new(State::new_limiter()),
+            limiter: match config.limiter {
+                Backend::Memory => Limiter::Memory(Mutex::new(Limiter::new_rate_limiter())),
+                Backend::Postgres(pool) => Limiter::Postgres(pool),
+            },
+            inventory: match config.inventory {
+                Backend::Memory => Inventory::Memory(Mutex::new(Vec::new())),
+                Backend::Postgres(pool) => Inventory::Postgres(pool),
+            },
+            batch_tokens: config.batch_tokens,
+            rounding: config.rounding,
         }
     }
 }
 
-impl State {
-    fn new_limiter() -> RateLimiter {
-        RateLimiter::builder()
-            .initial(5)
-            .max(5)
-            .interval(Duration::from_millis(1000))
-            .build()
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Dimension {
+    Volume,
+    Mass,
+    Temperature,
+    Length,
+}
+
+#[derive(Debug)]
+struct Unit {
+    name: &'static str,
+    dimension: Dimension,
+    scale: Decimal,
+    offset: Decimal,
+}
+
+const fn unit(name: &'static str, dimension: Dimension, scale: Decimal) -> Unit {
+    Unit {
+        name,
+        dimension,
+        scale,
+        offset: Decimal::ZERO,
+    }
+}
+
+// Scales (and offsets) are relative to the base unit of each dimension:
+// liters, kilograms, rankine and meters. Rankine keeps every temperature
+// scale an exact decimal.
+const UNITS: &[Unit] = &[
+    unit("liters", Dimension::Volume, dec!(1)),
+    unit("litres", Dimension::Volume, dec!(1)),
+    unit("milliliters", Dimension::Volume, dec!(0.001)),
+    unit("millilitres", Dimension::Volume, dec!(0.001)),
+    unit("cubic_meters", Dimension::Volume, dec!(1000)),
+    unit("gallons", Dimension::Volume, dec!(3.785411784)),
+    unit("uk_gallons", Dimension::Volume, dec!(4.54609)),
+    unit("pints", Dimension::Volume, dec!(0.56826125)),
+    unit("us_pints", Dimension::Volume, dec!(0.473176473)),
+    unit("cups", Dimension::Volume, dec!(0.2365882365)),
+    unit("fluid_ounces", Dimension::Volume, dec!(0.0295735295625)),
+    unit("tablespoons", Dimension::Volume, dec!(0.01478676478125)),
+    unit("teaspoons", Dimension::Volume, dec!(0.00492892159375)),
+    unit("kilograms", Dimension::Mass, dec!(1)),
+    unit("grams", Dimension::Mass, dec!(0.001)),
+    unit("milligrams", Dimension::Mass, dec!(0.000001)),
+    unit("tonnes", Dimension::Mass, dec!(1000)),
+    unit("pounds", Dimension::Mass, dec!(0.45359237)),
+    unit("ounces", Dimension::Mass, dec!(0.028349523125)),
+    unit("rankine", Dimension::Temperature, dec!(1)),
+    unit("kelvin", Dimension::Temperature, dec!(1.8)),
+    Unit {
+        name: "celsius",
+        dimension: Dimension::Temperature,
+        scale: dec!(1.8),
+        offset: dec!(491.67),
+    },
+    Unit {
+        name: "fahrenheit",
+        dimension: Dimension::Temperature,
+        scale: dec!(1),
+        offset: dec!(459.67),
+    },
+    unit("meters", Dimension::Length, dec!(1)),
+    unit("metres", Dimension::Length, dec!(1)),
+    unit("millimeters", Dimension::Length, dec!(0.001)),
+    unit("centimeters", Dimension::Length, dec!(0.01)),
+    unit("kilometers", Dimension::Length, dec!(1000)),
+    unit("inches", Dimension::Length, dec!(0.0254)),
+    unit("feet", Dimension::Length, dec!(0.3048)),
+    unit("yards", Dimension::Length, dec!(0.9144)),
+    unit("miles", Dimension::Length, dec!(1609.344)),
+];
+
+impl Unit {
+    fn lookup(name: &str) -> Option<&'static Unit> {
+        UNITS.iter().find(|unit| unit.name == name)
+    }
+
+    // Target unit used when a request doesn't name one, keeping the original
+    // US (liters <-> gallons) and UK (litres <-> pints) request shapes working.
+    fn alias(&self) -> Option<&'static Unit> {
+        match self.name {
+            "liters" => Unit::lookup("gallons"),
+            "gallons" => Unit::lookup("liters"),
+            "litres" => Unit::lookup("pints"),
+            "pints" => Unit::lookup("litres"),
+            _ => None,
+        }
+    }
+
+    fn normalize(&self, value: f64) -> f64 {
+        value * self.scale.to_f64().unwrap() + self.offset.to_f64().unwrap()
     }
 
-    fn reset(&self) {
-        let mut rl = self.limiter.lock().unwrap();
-        *rl = State::new_limiter();
+    fn denormalize(&self, value: f64) -> f64 {
+        (value - self.offset.to_f64().unwrap()) / self.scale.to_f64().unwrap()
+    }
+
+    fn normalize_exact(&self, value: Decimal) -> Option<Decimal> {
+        value.checked_mul(self.scale)?.checked_add(self.offset)
+    }
+
+    fn denormalize_exact(&self, value: Decimal) -> Option<Decimal> {
+        value.checked_sub(self.offset)?.checked_div(self.scale)
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
-struct UsReq {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    liters: Option<f32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    gallons: Option<f32>,
+#[derive(Debug, Clone, Copy)]
+pub enum Rounding {
+    HalfEven,
+    HalfUp,
+    HalfDown,
+    Up,
+    Down,
+    Ceiling,
+    Floor,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
-struct UkReq {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    litres: Option<f32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pints: Option<f32>,
+impl FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half_even" => Ok(Rounding::HalfEven),
+            "half_up" => Ok(Rounding::HalfUp),
+            "half_down" => Ok(Rounding::HalfDown),
+            "up" => Ok(Rounding::Up),
+            "down" => Ok(Rounding::Down),
+            "ceiling" => Ok(Rounding::Ceiling),
+            "floor" => Ok(Rounding::Floor),
+            _ => Err(format!("Unknown rounding mode: {}", s)),
+        }
+    }
 }
 
-#[derive(Debug, Serialize)]
-#[serde(untagged)]
-enum Request {
-    Us(UsReq),
-    Uk(UkReq),
+impl Rounding {
+    fn strategy(self) -> RoundingStrategy {
+        match self {
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::HalfDown => RoundingStrategy::MidpointTowardZero,
+            Rounding::Up => RoundingStrategy::AwayFromZero,
+            Rounding::Down => RoundingStrategy::ToZero,
+            Rounding::Ceiling => RoundingStrategy::ToPositiveInfinity,
+            Rounding::Floor => RoundingStrategy::ToNegativeInfinity,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Precision {
+    digits: u32,
+    rounding: Rounding,
+}
+
+impl Precision {
+    // Read from the query string first, falling back to parameters of the
+    // Accept header, e.g. `Accept: application/json; precision=6`.
+    fn parse(
+        params: &HashMap<String, String>,
+        headers: &HeaderMap,
+        default_rounding: Rounding,
+    ) -> Result<Option<Self>, String> {
+        let accept_params = headers
+            .get(ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| {
+                value
+                    .split(';')
+                    .skip(1)
+                    .filter_map(|param| param.split_once('='))
+                    .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+                    .collect::<HashMap<_, _>>()
+            })
+            .unwrap_or_default();
+        let param = |key: &str| params.get(key).or_else(|| accept_params.get(key));
+
+        let rounding = match param("rounding") {
+            Some(rounding) => rounding.parse()?,
+            None => default_rounding,
+        };
+        match param("precision") {
+            Some(digits) => match digits.parse::<u32>() {
+                Ok(digits @ 1..=28) => Ok(Some(Precision { digits, rounding })),
+                _ => Err(format!("Invalid precision: {}", digits)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn format(&self, value: Decimal) -> Option<String> {
+        let mut rounded = value.round_sf_with_strategy(self.digits, self.rounding.strategy())?;
+        if rounded.is_zero() {
+            rounded = Decimal::ZERO;
+        }
+        let mantissa_digits = rounded.mantissa().unsigned_abs().to_string().len() as i64;
+        let exponent = mantissa_digits - 1 - rounded.scale() as i64;
+        let scale = (self.digits as i64 - 1 - exponent).clamp(0, 28);
+        rounded.rescale(scale as u32);
+        Some(rounded.to_string())
+    }
+}
+
+#[derive(Debug)]
+enum Amount {
+    Float(f64),
+    Decimal(String),
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Amount::Float(value) => serializer.serialize_f64(*value),
+            Amount::Decimal(value) => RawValue::from_string(value.clone())
+                .map_err(serde::ser::Error::custom)?
+                .serialize(serializer),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Converted {
+    unit: &'static str,
+    amount: Amount,
+}
+
+impl Serialize for Converted {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry(self.unit, &self.amount)?;
+        map.end()
+    }
+}
+
+#[derive(Debug)]
+struct Quantity {
+    unit: &'static Unit,
+    value: Number,
+}
+
+impl Quantity {
+    fn parse(object: Map<String, Value>) -> Result<Self, String> {
+        let mut fields = object.into_iter();
+        match (fields.next(), fields.next()) {
+            (Some((name, Value::Number(value))), None) => {
+                let unit = Unit::lookup(&name).ok_or_else(|| format!("Unknown unit: {}", name))?;
+                Ok(Quantity { unit, value })
+            }
+            _ => Err("Invalid request".to_string()),
+        }
+    }
+
+    fn parse_volume(body: &str) -> Result<Self, String> {
+        match serde_json::from_str::<Value>(body) {
+            Ok(Value::Object(object)) => {
+                let quantity = Quantity::parse(object)?;
+                if quantity.unit.dimension == Dimension::Volume {
+                    Ok(quantity)
+                } else {
+                    Err(format!("{} is not a unit of volume", quantity.unit.name))
+                }
+            }
+            _ => Err("Invalid request".to_string()),
+        }
+    }
+
+    fn exact(&self) -> Result<Decimal, String> {
+        let value = self.value.to_string();
+        Decimal::from_str_exact(&value)
+            .or_else(|_| Decimal::from_scientific(&value))
+            .map_err(|_| format!("Invalid value: {}", value))
+    }
+
+    fn liters(&self) -> Result<Decimal, String> {
+        match self.unit.normalize_exact(self.exact()?) {
+            Some(liters) if liters > Decimal::ZERO => Ok(liters),
+            Some(_) => Err("Amount must be positive".to_string()),
+            None => Err("Amount overflow".to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Request {
+    quantity: Quantity,
+    to: &'static Unit,
 }
 
 impl Request {
+    fn parse(value: Value) -> Result<Self, String> {
+        let mut object = match value {
+            Value::Object(object) => object,
+            _ => return Err("Invalid request".to_string()),
+        };
+
+        let to = match object.remove("to") {
+            Some(Value::String(to)) => {
+                Some(Unit::lookup(&to).ok_or_else(|| format!("Unknown unit: {}", to))?)
+            }
+            Some(_) => return Err("Invalid target unit".to_string()),
+            None => None,
+        };
+
+        let quantity = Quantity::parse(object)?;
+        let from = quantity.unit;
+        let to = to
+            .or_else(|| from.alias())
+            .ok_or_else(|| format!("No target unit for {}", from.name))?;
+        if from.dimension == to.dimension {
+            Ok(Request { quantity, to })
+        } else {
+            Err(format!("Cannot convert {} to {}", from.name, to.name))
+        }
+    }
+
+    fn convert(&self, precision: Option<&Precision>) -> Result<Converted, String> {
+        let from = self.quantity.unit;
+        let amount = match precision {
+            Some(precision) => from
+                .normalize_exact(self.quantity.exact()?)
+                .and_then(|value| self.to.denormalize_exact(value))
+                .and_then(|value| precision.format(value))
+                .map(Amount::Decimal)
+                .ok_or("Conversion overflow")?,
+            None => {
+                let value = self.quantity.value.as_f64().ok_or("Invalid value")?;
+                Amount::Float(self.to.denormalize(from.normalize(value)))
+            }
+        };
+        Ok(Converted {
+            unit: self.to.name,
+            amount,
+        })
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Output {
+    Single(Converted),
+    Batch(Vec<Converted>),
+}
+
+enum Conversion {
+    Single(Request),
+    Batch(Vec<Request>),
+}
+
+impl Conversion {
     fn parse(body: &str) -> Result<Self, String> {
-        match serde_json::from_str::<UsReq>(body) {
-            Ok(us) if us.liters.is_some() ^ us.gallons.is_some() => Ok(Request::Us(us)),
-            _ => match serde_json::from_str::<UkReq>(body) {
-                Ok(uk) if uk.litres.is_some() ^ uk.pints.is_some() => Ok(Request::Uk(uk)),
-                _ => Err("Invalid request".to_string()),
-            },
+        match serde_json::from_str::<Value>(body) {
+            Ok(Value::Array(items)) => items
+                .into_iter()
+                .map(Request::parse)
+                .collect::<Result<Vec<_>, _>>()
+                .map(Conversion::Batch),
+            Ok(value) => Request::parse(value).map(Conversion::Single),
+            Err(_) => Err("Invalid request".to_string()),
         }
     }
 
-    fn convert(&self) -> Request {
+    fn tokens(&self, batch_tokens: usize) -> usize {
         match self {
-            Request::Us(us) => Request::Us(UsReq {
-                liters: us.gallons.map(|g| g * 3.7854118),
-                gallons: us.liters.map(|l| l / 3.7854118),
-            }),
-            Request::Uk(uk) => Request::Uk(UkReq {
-                litres: uk.pints.map(|p| p / 1.759754),
-                pints: uk.litres.map(|l| l * 1.759754),
-            }),
+            Conversion::Single(_) => 1,
+            Conversion::Batch(_) => batch_tokens,
+        }
+    }
+
+    fn convert(&self, precision: Option<&Precision>) -> Result<Output, String> {
+        match self {
+            Conversion::Single(request) => request.convert(precision).map(Output::Single),
+            Conversion::Batch(requests) => requests
+                .iter()
+                .map(|request| request.convert(precision))
+                .collect::<Result<Vec<_>, _>>()
+                .map(Output::Batch),
         }
     }
 }
@@ -86,21 +598,32 @@ impl Request {
 pub async fn milk(
     milk_factory: Arc<State>,
     Path(op): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
     headers: HeaderMap,
     body: String,
 ) -> (StatusCode, String) {
     match op.as_str() {
         "milk" => {
-            if milk_factory.limiter.lock().unwrap().try_acquire(1) {
-                match headers.get(CONTENT_TYPE) {
-                    Some(ct) if ct == "application/json" => match Request::parse(&body) {
-                        Ok(request) => {
-                            let converted = request.convert();
-                            (StatusCode::OK, serde_json::to_string(&converted).unwrap())
+            let conversion = match headers.get(CONTENT_TYPE) {
+                Some(ct) if ct == "application/json" => Some(Conversion::parse(&body)),
+                _ => None,
+            };
+            let tokens = match &conversion {
+                Some(Ok(conversion)) => conversion.tokens(milk_factory.batch_tokens),
+                _ => 1,
+            };
+            if milk_factory.limiter.try_acquire(tokens).await {
+                match conversion {
+                    Some(Ok(conversion)) => {
+                        match Precision::parse(&params, &headers, milk_factory.rounding)
+                            .and_then(|precision| conversion.convert(precision.as_ref()))
+                        {
+                            Ok(output) => (StatusCode::OK, serde_json::to_string(&output).unwrap()),
+                            Err(_) => (StatusCode::BAD_REQUEST, "".to_string()),
                         }
-                        _ => (StatusCode::BAD_REQUEST, "".to_string()),
-                    },
-                    _ => (StatusCode::OK, "Milk withdrawn\n".to_string()),
+                    }
+                    Some(Err(_)) => (StatusCode::BAD_REQUEST, "".to_string()),
+                    None => (StatusCode::OK, "Milk withdrawn\n".to_string()),
                 }
             } else {
                 (
@@ -109,10 +632,99 @@ pub async fn milk(
                 )
             }
         }
-        "refill" => {
-            milk_factory.reset();
-            (StatusCode::OK, "".to_string())
-        }
+        // Both movements need a JSON amount, checked before the bucket is
+        // touched. A refill without one only refills the bucket.
+        "withdraw" => match amount(&headers, &body) {
+            Some(Ok(liters)) => {
+                if milk_factory.limiter.try_acquire(1).await {
+                    record(&milk_factory, Movement::Withdrawal, liters).await
+                } else {
+                    (
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "No milk available\n".to_string(),
+                    )
+                }
+            }
+            _ => (StatusCode::BAD_REQUEST, "".to_string()),
+        },
+        "refill" => match amount(&headers, &body) {
+            Some(Ok(liters)) => {
+                milk_factory.limiter.reset().await;
+                record(&milk_factory, Movement::Refill, liters).await
+            }
+            Some(Err(_)) => (StatusCode::BAD_REQUEST, "".to_string()),
+            None => {
+                milk_factory.limiter.reset().await;
+                (StatusCode::OK, "".to_string())
+            }
+        },
         _ => (StatusCode::BAD_REQUEST, "".to_string()),
     }
 }
+
+pub async fn history(milk_factory: Arc<State>, Path(op): Path<String>) -> (StatusCode, String) {
+    match op.as_str() {
+        "history" => {
+            let history = milk_factory.inventory.history().await;
+            (StatusCode::OK, serde_json::to_string(&history).unwrap())
+        }
+        _ => (StatusCode::NOT_FOUND, "".to_string()),
+    }
+}
+
+// The liters in a JSON body, `None` when the body isn't JSON.
+fn amount(headers: &HeaderMap, body: &str) -> Option<Result<Decimal, String>> {
+    match headers.get(CONTENT_TYPE) {
+        Some(ct) if ct == "application/json" => {
+            Some(Quantity::parse_volume(body).and_then(|quantity| quantity.liters()))
+        }
+        _ => None,
+    }
+}
+
+async fn record(milk_factory: &State, movement: Movement, liters: Decimal) -> (StatusCode, String) {
+    match milk_factory.inventory.record(movement, liters).await {
+        Some(entry) => (StatusCode::OK, serde_json::to_string(&entry).unwrap()),
+        None => (
+            StatusCode::CONFLICT,
+            "Not enough milk in stock\n".to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_rounds_to_significant_digits() {
+        let cases = [
+            (dec!(1.23456), 3, Rounding::HalfEven, "1.23"),
+            (dec!(0.000123456), 2, Rounding::HalfEven, "0.00012"),
+            (dec!(2.5), 1, Rounding::HalfEven, "2"),
+            (dec!(2.5), 1, Rounding::HalfUp, "3"),
+            (dec!(-2.5), 1, Rounding::Floor, "-3"),
+            (dec!(1.21), 2, Rounding::Ceiling, "1.3"),
+            // Trailing zeros are kept up to the requested digits.
+            (dec!(1.5), 4, Rounding::HalfEven, "1.500"),
+            (dec!(9.99), 2, Rounding::HalfEven, "10"),
+            (dec!(-0.0001), 1, Rounding::Down, "-0.0001"),
+            // Zero, including negative zero, has no sign.
+            (dec!(0), 3, Rounding::HalfEven, "0.00"),
+            (-dec!(0.00), 2, Rounding::HalfEven, "0.0"),
+            // Values of 10^digits or more keep their integer digits.
+            (dec!(123456), 3, Rounding::HalfEven, "123000"),
+            (dec!(1000), 2, Rounding::HalfEven, "1000"),
+        ];
+        for (value, digits, rounding, expected) in cases {
+            let precision = Precision { digits, rounding };
+            assert_eq!(
+                precision.format(value).as_deref(),
+                Some(expected),
+                "{} to {} digits",
+                value,
+                digits
+            );
+        }
+    }
+}
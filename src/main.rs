@@ -12,21 +12,90 @@ use axum::{
     routing::{get, post},
     Router,
 };
+use axum_extra::extract::cookie::SameSite;
+use shuttle_runtime::{CustomError, SecretStore};
 use shuttle_shared_db::Postgres;
 use sqlx::{migrate, PgPool};
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use tower_http::services::ServeDir;
 
 #[shuttle_runtime::main]
-async fn main(#[Postgres] pool: PgPool) -> shuttle_axum::ShuttleAxum {
-    let day9_state = Arc::new(day9::State::default());
-    let day12_state = Arc::new(day12::State::default());
+async fn main(
+    #[Postgres] pool: PgPool,
+    #[shuttle_runtime::Secrets] secrets: SecretStore,
+) -> shuttle_axum::ShuttleAxum {
+    // A batch costing more than the bucket holds could never be served.
+    let batch_tokens = checked_setting(&secrets, "DAY9_BATCH_TOKENS", 1)?;
+    if !(1..=day9::BUCKET_SIZE).contains(&batch_tokens) {
+        return Err(CustomError::msg(format!(
+            "DAY9_BATCH_TOKENS must be between 1 and {}",
+            day9::BUCKET_SIZE
+        ))
+        .into());
+    }
+    let day9_config = day9::Config {
+        batch_tokens,
+        limiter: match setting(&secrets, "DAY9_LIMITER").as_deref() {
+            Some("postgres") => day9::Backend::Postgres(pool.clone()),
+            _ => day9::Backend::Memory,
+        },
+        // The stock outlives restarts unless asked not to.
+        inventory: match setting(&secrets, "DAY9_INVENTORY").as_deref() {
+            Some("memory") => day9::Backend::Memory,
+            _ => day9::Backend::Postgres(pool.clone()),
+        },
+        rounding: checked_setting(&secrets, "DAY9_ROUNDING", day9::Rounding::HalfEven)?,
+    };
+    let day9_state = Arc::new(day9::State::new(day9_config));
+    let day12_config = day12::Config {
+        idle_timeout: Duration::from_secs(secret_or(&secrets, "DAY12_IDLE_TIMEOUT_SECS", 3600)),
+        pool: Some(pool.clone()),
+    };
+    let day12_state = Arc::new(day12::State::new(day12_config));
+
+    let gift_ttl = secret_or(&secrets, "DAY16_GIFT_TTL_SECS", 3600);
+    let day16_config = day16::Config {
+        secret: secret_or(&secrets, "DAY16_SECRET", "secret".to_string()),
+        key_dir: Some(secret_or(&secrets, "DAY16_KEY_DIR", PathBuf::from("keys"))),
+        default_kid: secret_or(
+            &secrets,
+            "DAY16_DEFAULT_KID",
+            "day16_santa_public_key".to_string(),
+        ),
+        jwks: setting(&secrets, "DAY16_JWKS"),
+        reload_interval: Duration::from_secs(secret_or(&secrets, "DAY16_KEY_RELOAD_SECS", 30)),
+        gift_ttl: Duration::from_secs(gift_ttl),
+        issuer: secret_or(&secrets, "DAY16_ISSUER", "santa".to_string()),
+        audience: secret_or(&secrets, "DAY16_AUDIENCE", "gift".to_string()),
+        leeway: Duration::from_secs(secret_or(&secrets, "DAY16_LEEWAY_SECS", 60)),
+        cookie: day16::CookieConfig {
+            http_only: secret_or(&secrets, "DAY16_COOKIE_HTTP_ONLY", true),
+            secure: secret_or(&secrets, "DAY16_COOKIE_SECURE", true),
+            same_site: match setting(&secrets, "DAY16_COOKIE_SAME_SITE").as_deref() {
+                Some("Strict") => SameSite::Strict,
+                Some("None") => SameSite::None,
+                _ => SameSite::Lax,
+            },
+            path: secret_or(&secrets, "DAY16_COOKIE_PATH", "/".to_string()),
+            // A Max-Age of 0 leaves it a session cookie.
+            max_age: Some(secret_or(&secrets, "DAY16_COOKIE_MAX_AGE_SECS", gift_ttl))
+                .filter(|max_age| *max_age > 0)
+                .map(Duration::from_secs),
+        },
+    };
+    let day16_state = Arc::new(day16::State::new(day16_config));
+    day16_state.watch();
 
     migrate!()
         .run(&pool)
         .await
         .expect("Failed to run migrations");
 
+    day12_state.restore().await;
+
     let router = Router::new()
         .nest_service("/assets", ServeDir::new("assets"))
         .route("/", get(day0::hello_bird))
@@ -35,26 +104,83 @@ async fn main(#[Postgres] pool: PgPool) -> shuttle_axum::ShuttleAxum {
         .route("/5/manifest", post(day5::manifest))
         .route(
             "/9/:op",
-            post({
+            get({
+                let shared_state = Arc::clone(&day9_state);
+                move |Path(op)| day9::history(shared_state, Path(op))
+            })
+            .post({
                 let shared_state = Arc::clone(&day9_state);
-                move |Path(op), headers, body| day9::milk(shared_state, Path(op), headers, body)
+                move |Path(op), Query(params), headers, body| {
+                    day9::milk(shared_state, Path(op), Query(params), headers, body)
+                }
             }),
         )
         .route(
             "/12/*op",
             get({
                 let shared_state = Arc::clone(&day12_state);
-                move |Path(op)| day12::board(shared_state, Path(op))
+                move |Path(op), Query(params), headers| {
+                    day12::board(shared_state, Path(op), Query(params), headers)
+                }
             })
             .post({
                 let shared_state = Arc::clone(&day12_state);
-                move |Path(op)| day12::game(shared_state, Path(op))
+                move |Path(op), Query(params), headers, body| {
+                    day12::game(shared_state, Path(op), Query(params), headers, body)
+                }
+            })
+            .put({
+                let shared_state = Arc::clone(&day12_state);
+                move |Path(op), headers, body| day12::load(shared_state, Path(op), headers, body)
+            }),
+        )
+        .route(
+            "/12/stream",
+            get({
+                let shared_state = Arc::clone(&day12_state);
+                move |Query(params)| day12::stream(shared_state, None, Query(params))
+            }),
+        )
+        .route(
+            "/12/play",
+            get({
+                let shared_state = Arc::clone(&day12_state);
+                move || day12::play(shared_state, None)
+            }),
+        )
+        .route(
+            "/12/games/:id/play",
+            get({
+                let shared_state = Arc::clone(&day12_state);
+                move |Path(id)| day12::play(shared_state, Some(id))
+            }),
+        )
+        .route(
+            "/12/games/:id/stream",
+            get({
+                let shared_state = Arc::clone(&day12_state);
+                move |Path(id), Query(params)| day12::stream(shared_state, Some(id), Query(params))
+            }),
+        )
+        .route(
+            "/.well-known/jwks.json",
+            get({
+                let shared_state = Arc::clone(&day16_state);
+                move || day16::jwks(shared_state)
             }),
         )
         .route(
             "/16/:op",
-            get(move |Path(op), headers| day16::unwrap(Path(op), headers))
-                .post(move |Path(op), body| day16::jwt(Path(op), body)),
+            get({
+                let shared_state = Arc::clone(&day16_state);
+                move |Path(op), jar| day16::unwrap(shared_state, Path(op), jar)
+            })
+            .post({
+                let shared_state = Arc::clone(&day16_state);
+                move |Path(op), Query(params), body| {
+                    day16::jwt(shared_state, Path(op), Query(params), body)
+                }
+            }),
         )
         .route(
             "/19/*op",
@@ -82,3 +208,29 @@ async fn main(#[Postgres] pool: PgPool) -> shuttle_axum::ShuttleAxum {
         );
     Ok(router.into())
 }
+
+// Reads `key` from the secrets, then the environment.
+fn setting(secrets: &SecretStore, key: &str) -> Option<String> {
+    secrets.get(key).or_else(|| std::env::var(key).ok())
+}
+
+fn secret_or<T: FromStr>(secrets: &SecretStore, key: &str, default: T) -> T {
+    setting(secrets, key)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+// Like `secret_or`, but a value that doesn't parse stops startup instead of
+// being replaced by the default.
+fn checked_setting<T: FromStr>(
+    secrets: &SecretStore,
+    key: &str,
+    default: T,
+) -> Result<T, CustomError> {
+    match setting(secrets, key) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| CustomError::msg(format!("Invalid {}: {}", key, value))),
+        None => Ok(default),
+    }
+}